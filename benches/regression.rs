@@ -31,7 +31,7 @@ impl ToMemcached for Data {
 impl FromMemcached for Data {
     fn get_value(flags: StoredType, buf: Vec<u8>) -> Result<Self> {
         assert!(flags == StoredType::MTYPE_USER_DEFINED_1);
-        Ok(serde_json::from_slice(&*buf).unwrap())
+        Ok(serde_json::from_slice(&buf).unwrap())
     }
 }
 
@@ -16,7 +16,7 @@ struct Data {
     registered: bool
 }
 
-impl<'a> ToMemcached for &'a Data {
+impl ToMemcached for &Data {
     fn get_value(&self) -> Result<(Vec<u8>, StoredType)> {
         Ok((serde_json::to_vec(self).unwrap(), StoredType::MTYPE_USER_DEFINED_1))
     }
@@ -25,7 +25,7 @@ impl<'a> ToMemcached for &'a Data {
 impl FromMemcached for Data {
     fn get_value(flags: StoredType, buf: Vec<u8>) -> Result<Self> {
         assert!(flags == StoredType::MTYPE_USER_DEFINED_1);
-        Ok(serde_json::from_slice(&*buf).unwrap())
+        Ok(serde_json::from_slice(&buf).unwrap())
     }
 }
 
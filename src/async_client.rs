@@ -0,0 +1,706 @@
+//! Async counterparts of `Protocol` and `MemcachedClient` running on tokio, enabled by the `tokio`
+//! feature. Requests are encoded and responses decoded the same way as the blocking ones, so the
+//! same `ToMemcached` and `FromMemcached` implementations work with both.
+//!
+//! Connections are multiplexed: each request gets its own opaque, so any number of them can wait
+//! for their response on the same connection at the same time.
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::client::{backoff, MAX_BACKOFF, MIN_BACKOFF};
+use crate::codec::{Command, Request, Response, ResponseFrame, HEADER_LENGTH};
+use crate::distribution::{ConsistentHashRing, Distributor};
+use crate::errors::{Error, ErrorKind, Result};
+use crate::protocol::{build_store_request, stored_type, FromMemcached, Status, ToMemcached};
+use crate::transport::UNIX_PREFIX;
+
+/// Fail with the status of `frame`, unless it is a success.
+fn success(frame: &ResponseFrame) -> Result<()> {
+    match frame.known_status() {
+        Some(Status::Success) => Ok(()),
+        Some(status) => bail!(ErrorKind::Status(status)),
+        None => bail!("Server sent an unknown status code 0x{:02x}", frame.status),
+    }
+}
+
+/// Why a connection can't be used anymore.
+#[derive(Clone, Debug)]
+enum Closed {
+    Io(io::ErrorKind, String),
+    Desynchronized(String),
+}
+
+impl Closed {
+    fn error(&self) -> Error {
+        match *self {
+            Closed::Io(kind, ref message) => io::Error::new(kind, message.clone()).into(),
+            Closed::Desynchronized(ref reason) => ErrorKind::Desynchronized(reason.clone()).into(),
+        }
+    }
+}
+
+impl From<io::Error> for Closed {
+    fn from(error: io::Error) -> Closed {
+        Closed::Io(error.kind(), error.to_string())
+    }
+}
+
+/// A request waiting for its response.
+#[derive(Debug)]
+struct Waiter {
+    opcode: u8,
+    sender: oneshot::Sender<Result<ResponseFrame>>,
+}
+
+/// Requests sent on a connection that are waiting for their response, by opaque.
+#[derive(Debug, Default)]
+struct Pending {
+    waiters: HashMap<u32, Waiter>,
+    closed: Option<Closed>,
+}
+
+/// Nothing can be left half done while `Pending` is locked, so a panic can't poison it.
+fn lock(pending: &Mutex<Pending>) -> MutexGuard<'_, Pending> {
+    pending.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Fail every waiting request and refuse new ones.
+fn close(pending: &Mutex<Pending>, closed: Closed) {
+    let mut pending = lock(pending);
+    warn!("Closing connection: {:?}", closed);
+    for (_, waiter) in pending.waiters.drain() {
+        let _ = waiter.sender.send(Err(closed.error()));
+    }
+    pending.closed.get_or_insert(closed);
+}
+
+/// Write the requests queued by `AsyncProtocol` until it is dropped. Requests queued while writing
+/// are sent together. Writing whole requests from a single task means a request that is cancelled
+/// can't leave half of it on the connection.
+async fn write_requests<W: AsyncWrite + Unpin>(
+    mut connection: BufWriter<W>,
+    mut requests: mpsc::UnboundedReceiver<Vec<u8>>,
+    pending: Arc<Mutex<Pending>>,
+) {
+    while let Some(request) = requests.recv().await {
+        let mut result = connection.write_all(&request).await;
+        while let (true, Ok(request)) = (result.is_ok(), requests.try_recv()) {
+            result = connection.write_all(&request).await;
+        }
+        if let Err(e) = result.and(connection.flush().await) {
+            return close(&pending, e.into());
+        }
+    }
+}
+
+/// Read responses and hand each one to the request with the same opaque, until the connection
+/// breaks or gets out of sync with the server.
+async fn read_responses<R: AsyncRead + Unpin>(
+    mut connection: BufReader<R>,
+    pending: Arc<Mutex<Pending>>,
+) {
+    loop {
+        let mut header = [0; HEADER_LENGTH];
+        if let Err(e) = connection.read_exact(&mut header).await {
+            return close(&pending, e.into());
+        }
+        let response = match Response::parse(&header) {
+            Ok(response) => response,
+            Err(reason) => return close(&pending, Closed::Desynchronized(reason)),
+        };
+        let waiter = lock(&pending).waiters.remove(&response.opaque);
+        let waiter = match waiter {
+            Some(ref waiter) if waiter.opcode != response.opcode => {
+                let reason = format!(
+                    "expected opcode 0x{:02x} for opaque {}, got opcode 0x{:02x}",
+                    waiter.opcode, response.opaque, response.opcode
+                );
+                return close(&pending, Closed::Desynchronized(reason));
+            }
+            Some(waiter) => waiter,
+            None => {
+                let reason = format!("no request with opaque {}", response.opaque);
+                return close(&pending, Closed::Desynchronized(reason));
+            }
+        };
+        let mut body = vec![0; response.body_length as usize];
+        if let Err(e) = connection.read_exact(&mut body).await {
+            let closed = Closed::from(e);
+            let _ = waiter.sender.send(Err(closed.error()));
+            return close(&pending, closed);
+        }
+        // The request may have been cancelled, its response is just dropped then.
+        let _ = waiter
+            .sender
+            .send(Ok(ResponseFrame::from_parts(response, body)));
+    }
+}
+
+/// A connection to a single server. Requests can be sent concurrently from any number of tasks,
+/// their responses are matched to them by opaque.
+#[derive(Debug)]
+pub struct AsyncProtocol {
+    requests: mpsc::UnboundedSender<Vec<u8>>,
+    pending: Arc<Mutex<Pending>>,
+    opaque: AtomicU32,
+    reader: JoinHandle<()>,
+    timeout: Option<Duration>,
+}
+
+impl Drop for AsyncProtocol {
+    fn drop(&mut self) {
+        // The writer stops by itself once `requests` is dropped.
+        self.reader.abort();
+    }
+}
+
+impl AsyncProtocol {
+    /// Connect to the server and start the tasks that write requests and read responses, so it
+    /// must be called from a tokio runtime.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<AsyncProtocol> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        Ok(AsyncProtocol::start(reader, writer))
+    }
+
+    /// Connect to a server listening on the Unix domain socket at `path`, like memcached started
+    /// with `-s`. Must be called from a tokio runtime as well.
+    #[cfg(unix)]
+    pub async fn connect_unix<P: AsRef<Path>>(path: P) -> Result<AsyncProtocol> {
+        let (reader, writer) = UnixStream::connect(path).await?.into_split();
+        Ok(AsyncProtocol::start(reader, writer))
+    }
+
+    fn start<R, W>(reader: R, writer: W) -> AsyncProtocol
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let (requests, queued) = mpsc::unbounded_channel();
+        tokio::spawn(write_requests(
+            BufWriter::new(writer),
+            queued,
+            pending.clone(),
+        ));
+        let reader = tokio::spawn(read_responses(BufReader::new(reader), pending.clone()));
+        AsyncProtocol {
+            requests,
+            pending,
+            opaque: AtomicU32::new(0),
+            reader,
+            timeout: None,
+        }
+    }
+
+    /// Fail requests that wait longer than `timeout` for their response with
+    /// `ErrorKind::Timeout`. The connection is closed then, along with the other requests waiting
+    /// on it, since the server may never answer them. Requests wait forever by default.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Whether the connection broke, after which every request fails.
+    pub fn is_closed(&self) -> bool {
+        lock(&self.pending).closed.is_some()
+    }
+
+    /// Send `request` with a new opaque and wait for the response with the same one.
+    async fn send(&self, mut request: Request, final_payload: &[u8]) -> Result<ResponseFrame> {
+        request.opaque = self.opaque.fetch_add(1, Ordering::Relaxed);
+        let mut buf = Vec::with_capacity(HEADER_LENGTH + final_payload.len());
+        request.write_to(&mut buf, final_payload)?;
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut pending = lock(&self.pending);
+            if let Some(ref closed) = pending.closed {
+                return Err(closed.error());
+            }
+            let waiter = Waiter {
+                opcode: request.opcode,
+                sender,
+            };
+            pending.waiters.insert(request.opaque, waiter);
+        }
+        // If the writer stopped, it already failed the waiter.
+        let _ = self.requests.send(buf);
+        let response = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, receiver).await {
+                Ok(response) => response,
+                Err(_) => {
+                    let reason = "Timed out waiting for a response".to_string();
+                    close(&self.pending, Closed::Io(io::ErrorKind::TimedOut, reason));
+                    bail!(ErrorKind::Timeout);
+                }
+            },
+            None => receiver.await,
+        };
+        match response {
+            Ok(result) => result,
+            Err(_) => {
+                Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Connection closed").into())
+            }
+        }
+    }
+
+    /// Authenticate using the SASL PLAIN mechanism.
+    pub async fn authenticate_plain(&self, username: &str, password: &str) -> Result<()> {
+        let mechanism = b"PLAIN";
+        let mut data = Vec::with_capacity(username.len() + password.len() + 2);
+        data.push(0);
+        data.extend_from_slice(username.as_bytes());
+        data.push(0);
+        data.extend_from_slice(password.as_bytes());
+        let request = Request::new(Command::SaslAuth, mechanism.len(), data.len(), 0, 0, 0x00)?;
+        let mut final_payload = mechanism.to_vec();
+        final_payload.extend_from_slice(&data);
+        let frame = self.send(request, &final_payload).await?;
+        match frame.known_status() {
+            Some(Status::AuthContinue) | Some(Status::SaslAuthContinue) => {
+                bail!(ErrorKind::AuthenticationFailed(
+                    "Server asked for another step on PLAIN authentication".into()
+                ))
+            }
+            Some(Status::AuthError) | Some(Status::SaslAuthError) => bail!(
+                ErrorKind::AuthenticationFailed(String::from_utf8_lossy(&frame.value).into_owned())
+            ),
+            _ => success(&frame),
+        }
+    }
+
+    pub async fn get<K, V>(&self, key: K) -> Result<V>
+    where
+        K: AsRef<[u8]>,
+        V: FromMemcached,
+    {
+        let key = key.as_ref();
+        let request = Request::new(Command::Get, key.len(), 0, 0, 0, 0x00)?;
+        let frame = self.send(request, key).await?;
+        success(&frame)?;
+        if frame.extras.len() != 4 {
+            bail!(
+                "Server sent {} bytes of flags instead of 4",
+                frame.extras.len()
+            );
+        }
+        let flags = stored_type(BigEndian::read_u32(&frame.extras))?;
+        FromMemcached::get_value(flags, frame.value)
+    }
+
+    pub async fn set<K, V>(&self, key: K, value: V, time: u32) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        self.store(Command::Set, key, value, time).await
+    }
+
+    pub async fn add<K, V>(&self, key: K, value: V, time: u32) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        self.store(Command::Add, key, value, time).await
+    }
+
+    pub async fn replace<K, V>(&self, key: K, value: V, time: u32) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        self.store(Command::Replace, key, value, time).await
+    }
+
+    async fn store<K, V>(&self, command: Command, key: K, value: V, time: u32) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        let (value, flags) = value.get_value()?;
+        let (request, final_payload) =
+            build_store_request(command, key.as_ref(), &value, flags.bits(), time, 0)?;
+        success(&self.send(request, &final_payload).await?)
+    }
+
+    /// Delete `key`, keys that do not exist are not considered failures.
+    pub async fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        let key = key.as_ref();
+        let request = Request::new(Command::Delete, key.len(), 0, 0, 0, 0x00)?;
+        let frame = self.send(request, key).await?;
+        match frame.known_status() {
+            Some(Status::KeyNotFound) => Ok(()),
+            _ => success(&frame),
+        }
+    }
+
+    pub async fn increment<K>(&self, key: K, amount: u64, initial: u64, time: u32) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.increment_decrement(Command::Increment, key, amount, initial, time)
+            .await
+    }
+
+    pub async fn decrement<K>(&self, key: K, amount: u64, initial: u64, time: u32) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.increment_decrement(Command::Decrement, key, amount, initial, time)
+            .await
+    }
+
+    async fn increment_decrement<K>(
+        &self,
+        command: Command,
+        key: K,
+        amount: u64,
+        initial: u64,
+        time: u32,
+    ) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        let mut final_payload = vec![0; 20]; // Amount: u64, Initial: u64, Time: u32
+        BigEndian::write_u64(&mut final_payload[0..8], amount);
+        BigEndian::write_u64(&mut final_payload[8..16], initial);
+        BigEndian::write_u32(&mut final_payload[16..20], time);
+        let request = Request::new(command, key.len(), 0, 0, final_payload.len(), 0x00)?;
+        final_payload.extend_from_slice(key);
+        let frame = self.send(request, &final_payload).await?;
+        success(&frame)?;
+        if frame.value.len() != 8 {
+            bail!(
+                "Server sent a counter of {} bytes instead of 8",
+                frame.value.len()
+            );
+        }
+        Ok(BigEndian::read_u64(&frame.value))
+    }
+}
+
+/// Default longest time to wait for a server to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Everything needed to open a new connection to a server.
+#[derive(Debug)]
+struct ConnectionConfig {
+    credentials: Option<(String, String)>,
+    connect_timeout: Duration,
+    timeout: Option<Duration>,
+    min_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl ConnectionConfig {
+    /// Connect to the server written `name`, giving up after the connect timeout.
+    async fn connect(&self, name: &str) -> Result<AsyncProtocol> {
+        match tokio::time::timeout(self.connect_timeout, self.open(name)).await {
+            Ok(result) => result,
+            Err(_) => bail!(ErrorKind::Timeout),
+        }
+    }
+
+    async fn open(&self, name: &str) -> Result<AsyncProtocol> {
+        let mut protocol = match name.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => AsyncProtocol::connect_unix(path).await?,
+            #[cfg(not(unix))]
+            Some(path) => bail!(
+                "Unix domain sockets are not supported on this platform: {}",
+                path
+            ),
+            None => AsyncProtocol::connect(name).await?,
+        };
+        protocol.set_timeout(self.timeout);
+        if let Some((ref username, ref password)) = self.credentials {
+            protocol.authenticate_plain(username, password).await?;
+        }
+        Ok(protocol)
+    }
+}
+
+/// A connection of an `AsyncServer`, reopened when it is used after breaking, with the same
+/// backoff as the connections of `MemcachedClient`.
+struct Slot {
+    protocol: Option<Arc<AsyncProtocol>>,
+    failed_attempts: u32,
+    next_attempt: Option<Instant>,
+}
+
+/// A server of an `AsyncMemcachedClient`, with connections that are reopened when they break.
+struct AsyncServer {
+    name: String,
+    connections: Vec<tokio::sync::Mutex<Slot>>,
+    next_connection: AtomicUsize,
+}
+
+impl AsyncServer {
+    /// The next connection in turn, which may be in use by other requests.
+    async fn connection(&self, config: &ConnectionConfig) -> Result<Arc<AsyncProtocol>> {
+        let index = self.next_connection.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+        // Only held while reconnecting, so a single task does it.
+        let mut slot = self.connections[index].lock().await;
+        match slot.protocol {
+            Some(ref protocol) if !protocol.is_closed() => return Ok(protocol.clone()),
+            Some(_) => warn!("Reconnecting to {}", self.name),
+            None => {}
+        }
+        if let Some(next_attempt) = slot.next_attempt {
+            if Instant::now() < next_attempt {
+                bail!(ErrorKind::Disconnected(self.name.clone()));
+            }
+        }
+        match config.connect(&self.name).await {
+            Ok(protocol) => {
+                let protocol = Arc::new(protocol);
+                slot.protocol = Some(protocol.clone());
+                slot.failed_attempts = 0;
+                slot.next_attempt = None;
+                Ok(protocol)
+            }
+            Err(e) => {
+                let backoff = backoff(config.min_backoff, config.max_backoff, slot.failed_attempts);
+                slot.protocol = None;
+                slot.next_attempt = Some(Instant::now() + backoff);
+                slot.failed_attempts += 1;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Async client for a pool of servers, with keys distributed by a `ConsistentHashRing` by default
+/// just like `MemcachedClient`. Broken connections are reopened with the same backoff as the ones
+/// of `MemcachedClient`. TLS is not supported yet, use `MemcachedClient` for that.
+pub struct AsyncMemcachedClient {
+    distributor: Box<dyn Distributor>,
+    servers: Vec<AsyncServer>,
+    config: ConnectionConfig,
+}
+
+/// Configure an `AsyncMemcachedClient` before connecting to the servers, the same way as
+/// `MemcachedClientBuilder`.
+///
+/// ```no_run
+/// # async fn example() {
+/// use std::time::Duration;
+///
+/// use bmemcached::AsyncMemcachedClient;
+///
+/// let client = AsyncMemcachedClient::builder(vec!["127.0.0.1:11211"])
+///     .server("127.0.0.1:11212", 4)
+///     .connections_per_addr(5)
+///     .credentials("user", "password")
+///     .timeout(Duration::from_millis(500))
+///     .build()
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+pub struct AsyncMemcachedClientBuilder<A> {
+    addrs: Vec<(A, usize)>,
+    connections_per_addr: u8,
+    config: ConnectionConfig,
+    distributor: Box<dyn Distributor>,
+}
+
+impl<A: Display> AsyncMemcachedClientBuilder<A> {
+    /// Add a server that gets `weight` times the share of the keys of a server with a weight of 1,
+    /// like the servers given to `AsyncMemcachedClient::builder`.
+    pub fn server(mut self, addr: A, weight: usize) -> Self {
+        self.addrs.push((addr, weight));
+        self
+    }
+
+    /// How many connections will be opened to each server, defaults to 1. Each connection is
+    /// shared by any number of concurrent requests.
+    pub fn connections_per_addr(mut self, connections_per_addr: u8) -> Self {
+        self.connections_per_addr = connections_per_addr;
+        self
+    }
+
+    /// Authenticate every connection with SASL PLAIN right after connecting.
+    pub fn credentials<U, P>(mut self, username: U, password: P) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.config.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Maximum time to wait while connecting and authenticating to a server, defaults to 5
+    /// seconds.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    /// Maximum time to wait for the response to a request, after that it fails with
+    /// `ErrorKind::Timeout` and the connection is reopened. Requests wait forever by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Same as `MemcachedClientBuilder::reconnect_backoff`.
+    pub fn reconnect_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.config.min_backoff = min;
+        self.config.max_backoff = max;
+        self
+    }
+
+    /// How keys are spread between the servers, defaults to `ConsistentHashRing`. Check
+    /// `MemcachedClientBuilder::distributor`.
+    pub fn distributor<D: Distributor + 'static>(mut self, distributor: D) -> Self {
+        self.distributor = Box::new(distributor);
+        self
+    }
+
+    /// Open the connections to every server, so it must be called from a tokio runtime.
+    pub async fn build(self) -> Result<AsyncMemcachedClient> {
+        if self.connections_per_addr == 0 {
+            bail!("At least one connection per server is needed");
+        }
+        let mut client = AsyncMemcachedClient {
+            distributor: self.distributor,
+            servers: Vec::with_capacity(self.addrs.len()),
+            config: self.config,
+        };
+        for (addr, weight) in self.addrs {
+            let name = addr.to_string();
+            if client.servers.iter().any(|server| server.name == name) {
+                bail!("{} is already in the pool", name);
+            }
+            let mut connections = Vec::with_capacity(self.connections_per_addr as usize);
+            for _ in 0..self.connections_per_addr {
+                let protocol = client.config.connect(&name).await?;
+                connections.push(tokio::sync::Mutex::new(Slot {
+                    protocol: Some(Arc::new(protocol)),
+                    failed_attempts: 0,
+                    next_attempt: None,
+                }));
+            }
+            client.distributor.add(&name, weight);
+            client.servers.push(AsyncServer {
+                name,
+                connections,
+                next_connection: AtomicUsize::new(0),
+            });
+        }
+        Ok(client)
+    }
+}
+
+impl AsyncMemcachedClient {
+    /// Open `connections_per_addr` connections to each server in `addrs`. Servers are identified
+    /// by their address as it is written, as `MemcachedClient` does, and `unix:` followed by a
+    /// path is a Unix domain socket.
+    pub async fn new<A: Display>(
+        addrs: Vec<A>,
+        connections_per_addr: u8,
+    ) -> Result<AsyncMemcachedClient> {
+        AsyncMemcachedClient::builder(addrs)
+            .connections_per_addr(connections_per_addr)
+            .build()
+            .await
+    }
+
+    pub fn builder<A: Display>(addrs: Vec<A>) -> AsyncMemcachedClientBuilder<A> {
+        AsyncMemcachedClientBuilder {
+            addrs: addrs.into_iter().map(|addr| (addr, 1)).collect(),
+            connections_per_addr: 1,
+            config: ConnectionConfig {
+                credentials: None,
+                connect_timeout: CONNECT_TIMEOUT,
+                timeout: None,
+                min_backoff: MIN_BACKOFF,
+                max_backoff: MAX_BACKOFF,
+            },
+            distributor: Box::new(ConsistentHashRing::new()),
+        }
+    }
+
+    /// A connection to the server that holds `key`.
+    async fn connection(&self, key: &[u8]) -> Result<Arc<AsyncProtocol>> {
+        let server = self
+            .distributor
+            .get(key)
+            .and_then(|name| self.servers.iter().find(|server| server.name == name));
+        match server {
+            Some(server) => server.connection(&self.config).await,
+            None => bail!(ErrorKind::NoServers),
+        }
+    }
+
+    pub async fn get<K, V>(&self, key: K) -> Result<V>
+    where
+        K: AsRef<[u8]>,
+        V: FromMemcached,
+    {
+        let connection = self.connection(key.as_ref()).await?;
+        connection.get(key).await
+    }
+
+    pub async fn set<K, V>(&self, key: K, value: V, time: u32) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        let connection = self.connection(key.as_ref()).await?;
+        connection.set(key, value, time).await
+    }
+
+    pub async fn add<K, V>(&self, key: K, value: V, time: u32) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        let connection = self.connection(key.as_ref()).await?;
+        connection.add(key, value, time).await
+    }
+
+    pub async fn replace<K, V>(&self, key: K, value: V, time: u32) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        let connection = self.connection(key.as_ref()).await?;
+        connection.replace(key, value, time).await
+    }
+
+    pub async fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        let connection = self.connection(key.as_ref()).await?;
+        connection.delete(key).await
+    }
+
+    pub async fn increment<K>(&self, key: K, amount: u64, initial: u64, time: u32) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+    {
+        let connection = self.connection(key.as_ref()).await?;
+        connection.increment(key, amount, initial, time).await
+    }
+
+    pub async fn decrement<K>(&self, key: K, amount: u64, initial: u64, time: u32) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+    {
+        let connection = self.connection(key.as_ref()).await?;
+        connection.decrement(key, amount, initial, time).await
+    }
+}
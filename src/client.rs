@@ -1,45 +1,789 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
 use std::net::ToSocketAddrs;
-use std::sync::{Arc, Mutex};
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, TryLockError};
+use std::time::{Duration, Instant};
 
-use conhash::{ConsistentHash, Node};
+use crate::distribution::{ConsistentHashRing, Distributor};
+use crate::errors::{Error, ErrorKind, Result};
+use crate::protocol::{self, Status};
+use crate::stats::ServerStats;
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsConnector};
+use crate::transport::{Address, Transport};
 
-use errors::Result;
-use protocol;
+/// Default wait before reconnecting after a failed attempt, doubled after each one up to
+/// `MAX_BACKOFF`.
+pub(crate) const MIN_BACKOFF: Duration = Duration::from_millis(100);
+pub(crate) const MAX_BACKOFF: Duration = Duration::from_secs(30);
 
-#[derive(Debug, Clone)]
-struct ClonableProtocol {
-    connection: Arc<Mutex<protocol::Protocol>>,
+/// Longest time to wait for an ejected server while checking if it is back, since that holds up
+/// the request that happens to probe it.
+const PROBE_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Everything needed to open a new connection to a server.
+#[derive(Debug)]
+struct ConnectionConfig {
+    credentials: Option<(String, String)>,
+    timeouts: protocol::Timeouts,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConnector>,
+}
+
+impl ConnectionConfig {
+    fn connect(
+        &self,
+        name: &str,
+        address: &Address,
+        timeouts: protocol::Timeouts,
+    ) -> Result<protocol::Protocol<Transport>> {
+        let mut protocol = protocol::Protocol::new(self.open(name, address, timeouts)?);
+        if let Some((ref username, ref password)) = self.credentials {
+            protocol.authenticate_plain(username, password)?;
+        }
+        Ok(protocol)
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn open(
+        &self,
+        _name: &str,
+        address: &Address,
+        timeouts: protocol::Timeouts,
+    ) -> Result<Transport> {
+        Transport::connect(address, timeouts)
+    }
+
+    #[cfg(feature = "tls")]
+    fn open(
+        &self,
+        name: &str,
+        address: &Address,
+        timeouts: protocol::Timeouts,
+    ) -> Result<Transport> {
+        match self.tls {
+            Some(ref tls) => {
+                let stream = tls.connect(name, address, timeouts)?;
+                Ok(Transport::Tls(Box::new(stream)))
+            }
+            None => Transport::connect(address, timeouts),
+        }
+    }
+
+    /// Timeouts of the connections checking if an ejected server is back.
+    fn probe_timeouts(&self) -> protocol::Timeouts {
+        let connect = match self.timeouts.connect {
+            Some(timeout) => timeout.min(PROBE_CONNECT_TIMEOUT),
+            None => PROBE_CONNECT_TIMEOUT,
+        };
+        protocol::Timeouts {
+            connect: Some(connect),
+            ..self.timeouts
+        }
+    }
+}
+
+/// TLS settings given to the builder, the certificates are loaded by `build`.
+#[cfg(feature = "tls")]
+#[derive(Debug, Default)]
+struct TlsFiles {
+    ca_bundle: Option<PathBuf>,
+    client_cert: Option<(PathBuf, PathBuf)>,
+    server_name: Option<String>,
+}
+
+#[cfg(feature = "tls")]
+impl TlsFiles {
+    fn connector(self) -> Result<Option<TlsConnector>> {
+        let ca_bundle = match self.ca_bundle {
+            Some(ca_bundle) => ca_bundle,
+            None if self.client_cert.is_none() && self.server_name.is_none() => return Ok(None),
+            None => bail!("A TLS CA bundle is needed to check the server certificates"),
+        };
+        let client_cert = self
+            .client_cert
+            .as_ref()
+            .map(|(cert, key)| (cert.as_path(), key.as_path()));
+        Ok(Some(TlsConnector {
+            config: tls::client_config(&ca_bundle, client_cert)?,
+            server_name: self.server_name,
+        }))
+    }
+}
+
+/// How long to wait before connecting again after `failed_attempts`. Exponential backoff so a dead
+/// server is not flooded with connection attempts.
+pub(crate) fn backoff(min: Duration, max: Duration, failed_attempts: u32) -> Duration {
+    min.checked_mul(1 << failed_attempts.min(16))
+        .map_or(max, |backoff| backoff.min(max))
+}
+
+/// Whether `error` may have left the connection in an unknown state, like in the middle of a
+/// response, so it can't be used anymore.
+fn breaks_connection(error: &Error) -> bool {
+    !matches!(
+        *error.kind(),
+        ErrorKind::Status(_)
+            | ErrorKind::TypeMismatch(_)
+            | ErrorKind::CasMismatch
+            | ErrorKind::KeyLengthTooLong(_)
+            | ErrorKind::UnknownFlags(_)
+            | ErrorKind::Utf8Error(_)
+    )
+}
+
+/// A connection to a server that is dropped once it breaks and reopened the next time it is used.
+#[derive(Debug)]
+struct Connection {
+    name: String,
+    config: Arc<ConnectionConfig>,
+    protocol: Option<protocol::Protocol<Transport>>,
+    failed_attempts: u32,
+    next_attempt: Option<Instant>,
+}
+
+impl Connection {
+    fn reconnect(
+        &mut self,
+        timeouts: protocol::Timeouts,
+    ) -> Result<&mut protocol::Protocol<Transport>> {
+        if let Some(next_attempt) = self.next_attempt {
+            if Instant::now() < next_attempt {
+                bail!(ErrorKind::Disconnected(self.name.clone()));
+            }
+        }
+        info!("Reconnecting to {}", self.name);
+        // Resolve the name again, the server may be back on another IP.
+        let connected = Address::resolve(&self.name)
+            .and_then(|address| self.config.connect(&self.name, &address, timeouts));
+        match connected {
+            Ok(protocol) => {
+                self.failed_attempts = 0;
+                self.next_attempt = None;
+                Ok(self.protocol.get_or_insert(protocol))
+            }
+            Err(e) => {
+                let backoff = backoff(
+                    self.config.min_backoff,
+                    self.config.max_backoff,
+                    self.failed_attempts,
+                );
+                self.failed_attempts += 1;
+                self.next_attempt = Some(Instant::now() + backoff);
+                Err(e)
+            }
+        }
+    }
+
+    fn with<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut protocol::Protocol<Transport>) -> Result<T>,
+    {
+        let timeouts = self.config.timeouts;
+        let result = match self.protocol {
+            Some(ref mut protocol) => f(protocol),
+            None => f(self.reconnect(timeouts)?),
+        };
+        if let Err(ref e) = result {
+            if breaks_connection(e) {
+                warn!("Dropping connection to {} after error: {}", self.name, e);
+                self.protocol = None;
+            }
+        }
+        result
+    }
+}
+
+/// A server, its health and the connections to it.
+#[derive(Debug)]
+struct Server {
+    name: String,
+    /// What `name` resolved to when the server was added, to find it in the pool. Connections
+    /// resolve the name again when they reconnect.
+    address: Address,
+    weight: usize,
+    consecutive_failures: AtomicUsize,
+    connections: Vec<Mutex<Connection>>,
+    next_connection: AtomicUsize,
+}
+
+/// Lock `connection`, also when a thread panicked while using it. In that case the stream is
+/// dropped since it could be in the middle of a response.
+fn lock(connection: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
+    connection
+        .lock()
+        .unwrap_or_else(|poisoned| recover(connection, poisoned))
+}
+
+fn recover<'a>(
+    connection: &Mutex<Connection>,
+    poisoned: PoisonError<MutexGuard<'a, Connection>>,
+) -> MutexGuard<'a, Connection> {
+    let mut guard = poisoned.into_inner();
+    warn!("Dropping connection to {} after a panic", guard.name);
+    guard.protocol = None;
+    connection.clear_poison();
+    guard
 }
 
-impl Node for ClonableProtocol {
-    fn name(&self) -> String {
-        let protocol = self.clone();
-        let connection = protocol.connection.lock().unwrap();
-        connection.connection_info()
+impl Server {
+    fn is_at(&self, address: &Address) -> bool {
+        self.address.overlaps(address)
+    }
+
+    /// Run `f` on any idle connection, or wait for one of them if they are all busy.
+    fn with<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut protocol::Protocol<Transport>) -> Result<T>,
+    {
+        // Start from a different connection every time so they are all used evenly.
+        let first = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        let count = self.connections.len();
+        for i in 0..count {
+            let connection = &self.connections[(first + i) % count];
+            match connection.try_lock() {
+                Ok(mut guard) => return guard.with(f),
+                Err(TryLockError::Poisoned(poisoned)) => {
+                    return recover(connection, poisoned).with(f)
+                }
+                Err(TryLockError::WouldBlock) => {}
+            }
+        }
+        lock(&self.connections[first % count]).with(f)
+    }
+
+    /// Check if the server is back, reconnecting right away even if the backoff is not over.
+    fn probe(&self) -> Result<()> {
+        let mut connection = lock(&self.connections[0]);
+        if connection.protocol.is_none() {
+            connection.next_attempt = None;
+            let timeouts = connection.config.probe_timeouts();
+            connection.reconnect(timeouts)?;
+        }
+        connection.with(|protocol| protocol.noop())
+    }
+}
+
+/// Settings to remove failing servers from the hash ring.
+#[derive(Debug, Clone, Copy)]
+struct Ejection {
+    failure_limit: usize,
+    retry_interval: Duration,
+}
+
+/// A server removed from the hash ring and when it should be probed again.
+#[derive(Debug)]
+struct EjectedServer {
+    server: Arc<Server>,
+    retry_at: Instant,
+}
+
+/// What a command split between several servers returned. A failing server doesn't lose the
+/// answers of the others, its error is kept in `failed` and its keys are missing from `values`.
+#[derive(Debug)]
+pub struct MultiResult<T> {
+    /// The answers of the servers that succeeded, merged.
+    pub values: T,
+    /// The name of each server that failed, with its error.
+    pub failed: Vec<(String, Error)>,
+}
+
+impl<T> MultiResult<T> {
+    /// The values if every server answered, otherwise the error of the first one that failed.
+    pub fn into_result(self) -> Result<T> {
+        match self.failed.into_iter().next() {
+            Some((_, error)) => Err(error),
+            None => Ok(self.values),
+        }
     }
 }
 
 /// Struct that holds all connections and proxy commands to the right server based on the key
 pub struct MemcachedClient {
-    connections: ConsistentHash<ClonableProtocol>,
+    distributor: RwLock<Box<dyn Distributor>>,
+    pool: RwLock<Vec<Arc<Server>>>,
+    config: Arc<ConnectionConfig>,
+    connections_per_addr: u8,
+    ejection: Option<Ejection>,
+    ejected: Mutex<Vec<EjectedServer>>,
+    created: Instant,
+    /// When the next ejected server should be probed, in milliseconds since `created`, so keyed
+    /// requests only lock `ejected` when there is something to do.
+    next_probe: AtomicU64,
+}
+
+/// Configure a `MemcachedClient` before connecting to the servers.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use bmemcached::MemcachedClient;
+///
+/// let client = MemcachedClient::builder(vec!["127.0.0.1:11211"])
+///     .server("127.0.0.1:11212", 4)
+///     .connections_per_addr(5)
+///     .credentials("user", "password")
+///     .read_timeout(Duration::from_millis(500))
+///     .build()
+///     .unwrap();
+/// ```
+pub struct MemcachedClientBuilder<A> {
+    addrs: Vec<(A, usize)>,
+    connections_per_addr: u8,
+    config: ConnectionConfig,
+    ejection: Option<Ejection>,
+    distributor: Box<dyn Distributor>,
+    #[cfg(feature = "tls")]
+    tls: TlsFiles,
+}
+
+impl<A: ToSocketAddrs + Display> MemcachedClientBuilder<A> {
+    /// Add a server that gets `weight` times the share of the keys of a server with a weight of 1,
+    /// like the servers given to `MemcachedClient::builder`. The weight doesn't depend on the
+    /// number of connections opened to the server.
+    pub fn server(mut self, addr: A, weight: usize) -> Self {
+        self.addrs.push((addr, weight));
+        self
+    }
+
+    /// How many connections will be opened to each server, defaults to 1.
+    pub fn connections_per_addr(mut self, connections_per_addr: u8) -> Self {
+        self.connections_per_addr = connections_per_addr;
+        self
+    }
+
+    /// Authenticate every connection with SASL PLAIN right after connecting.
+    pub fn credentials<U, P>(mut self, username: U, password: P) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.config.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Maximum time to wait while connecting to a server.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeouts.connect = Some(timeout);
+        self
+    }
+
+    /// Maximum time to wait for a server to answer, after that requests fail with
+    /// `ErrorKind::Timeout`.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeouts.read = Some(timeout);
+        self
+    }
+
+    /// Maximum time to wait while sending a request to a server, after that requests fail with
+    /// `ErrorKind::Timeout`.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeouts.write = Some(timeout);
+        self
+    }
+
+    /// Broken connections are reopened the next time they are used. When that fails, requests to
+    /// the server fail with `ErrorKind::Disconnected` for `min` before trying again, doubling the
+    /// wait after each failed attempt up to `max`. Defaults to 100 milliseconds and 30 seconds.
+    pub fn reconnect_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.config.min_backoff = min;
+        self.config.max_backoff = max;
+        self
+    }
+
+    /// Remove a server from the hash ring after `failure_limit` consecutive failures, so its keys
+    /// go to the other servers. Every `retry_interval` the server is checked and, once it answers
+    /// again, put back in the ring. The check is done by the next request and waits at most a
+    /// second, or the connect timeout if it is shorter, for the server to accept a connection.
+    /// Disabled by default.
+    pub fn auto_eject(mut self, failure_limit: usize, retry_interval: Duration) -> Self {
+        self.ejection = Some(Ejection {
+            failure_limit,
+            retry_interval,
+        });
+        self
+    }
+
+    /// How keys are spread between the servers, defaults to `ConsistentHashRing`. Use
+    /// `distribution::Ketama` to find keys stored by libmemcached based clients, check the
+    /// `distribution` module for the other strategies.
+    pub fn distributor<D: Distributor + 'static>(mut self, distributor: D) -> Self {
+        self.distributor = Box::new(distributor);
+        self
+    }
+
+    /// Encrypt the connections with TLS, trusting the server certificates signed by the
+    /// authorities in the PEM file at `path`. Needs the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub fn tls_ca_bundle<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.tls.ca_bundle = Some(path.into());
+        self
+    }
+
+    /// Present the certificate chain and private key of these PEM files to servers that require
+    /// client certificates, like memcached started with `-Z -o ssl_verify_mode=2`.
+    #[cfg(feature = "tls")]
+    pub fn tls_client_cert<C, K>(mut self, cert: C, key: K) -> Self
+    where
+        C: Into<PathBuf>,
+        K: Into<PathBuf>,
+    {
+        self.tls.client_cert = Some((cert.into(), key.into()));
+        self
+    }
+
+    /// Name checked against the certificate of every server and sent with SNI, instead of the
+    /// host of each server address. Useful when servers are written as IPs that are not in their
+    /// certificates.
+    #[cfg(feature = "tls")]
+    pub fn tls_server_name<N: Into<String>>(mut self, name: N) -> Self {
+        self.tls.server_name = Some(name.into());
+        self
+    }
+
+    #[cfg_attr(not(feature = "tls"), allow(unused_mut))]
+    pub fn build(mut self) -> Result<MemcachedClient> {
+        #[cfg(feature = "tls")]
+        {
+            self.config.tls = self.tls.connector()?;
+        }
+        let client = MemcachedClient {
+            distributor: RwLock::new(self.distributor),
+            pool: RwLock::new(vec![]),
+            config: Arc::new(self.config),
+            connections_per_addr: self.connections_per_addr,
+            ejection: self.ejection,
+            ejected: Mutex::new(vec![]),
+            created: Instant::now(),
+            next_probe: AtomicU64::new(u64::MAX),
+        };
+        for (addr, weight) in self.addrs {
+            client.add_server(addr, weight)?;
+        }
+        Ok(client)
+    }
 }
 
 impl MemcachedClient {
-    pub fn new<A: ToSocketAddrs>(
+    pub fn new<A: ToSocketAddrs + Display>(
         addrs: Vec<A>,
         connections_per_addr: u8,
     ) -> Result<MemcachedClient> {
-        let mut ch = ConsistentHash::new();
-        for addr in &addrs {
-            for _ in 0..connections_per_addr {
-                let protocol = protocol::Protocol::connect(addr)?;
-                ch.add(
-                    &ClonableProtocol { connection: Arc::new(Mutex::new(protocol)) },
-                    1,
-                );
+        MemcachedClient::builder(addrs)
+            .connections_per_addr(connections_per_addr)
+            .build()
+    }
+
+    pub fn builder<A: ToSocketAddrs + Display>(addrs: Vec<A>) -> MemcachedClientBuilder<A> {
+        MemcachedClientBuilder {
+            addrs: addrs.into_iter().map(|addr| (addr, 1)).collect(),
+            connections_per_addr: 1,
+            config: ConnectionConfig {
+                credentials: None,
+                timeouts: protocol::Timeouts::default(),
+                min_backoff: MIN_BACKOFF,
+                max_backoff: MAX_BACKOFF,
+                #[cfg(feature = "tls")]
+                tls: None,
+            },
+            ejection: None,
+            distributor: Box::new(ConsistentHashRing::new()),
+            #[cfg(feature = "tls")]
+            tls: TlsFiles::default(),
+        }
+    }
+
+    /// Connect to a new server and start sending it its share of the keys. The share of each server
+    /// is proportional to its `weight`, and only the keys that now belong to the new server are
+    /// moved. Fails if the server is already in the pool.
+    ///
+    /// The server is identified by `addr` as it is written, like `cache1:11211`, and not by the
+    /// address it resolves to. Its keys don't change when its IP does, and broken connections are
+    /// reopened to whatever the name resolves to at that time, but every client must write it the
+    /// same way to find the same keys. Servers listening on a Unix domain socket are written
+    /// `unix:` followed by the path of the socket, like `unix:/var/run/memcached.sock`.
+    pub fn add_server<A: ToSocketAddrs + Display>(&self, addr: A, weight: usize) -> Result<()> {
+        if self.connections_per_addr == 0 {
+            bail!("At least one connection per server is needed");
+        }
+        let name = addr.to_string();
+        let address = Address::resolve(&addr)?;
+        let protocols = (0..self.connections_per_addr)
+            .map(|_| self.config.connect(&name, &address, self.config.timeouts))
+            .collect::<Result<Vec<_>>>()?;
+        let connections = protocols
+            .into_iter()
+            .map(|protocol| {
+                Mutex::new(Connection {
+                    name: name.clone(),
+                    config: self.config.clone(),
+                    protocol: Some(protocol),
+                    failed_attempts: 0,
+                    next_attempt: None,
+                })
+            })
+            .collect();
+        let server = Arc::new(Server {
+            name: name.clone(),
+            address: address.clone(),
+            weight,
+            consecutive_failures: AtomicUsize::new(0),
+            connections,
+            next_connection: AtomicUsize::new(0),
+        });
+
+        let mut pool = self.pool.write()?;
+        if pool.iter().any(|s| s.name == name || s.is_at(&address)) {
+            bail!("Server {} is already in the pool", name);
+        }
+        pool.push(server);
+        self.distributor.write()?.add(&name, weight);
+        Ok(())
+    }
+
+    /// Stop sending requests to a server and close its connections once the requests in flight
+    /// are done. Only the keys of the removed server are moved. Returns whether the server was in
+    /// the pool, found either by the address it was added with or by what it resolves to.
+    pub fn remove_server<A: ToSocketAddrs + Display>(&self, addr: A) -> Result<bool> {
+        let name = addr.to_string();
+        // Servers are often removed because they are gone, their name may not resolve anymore.
+        let address = Address::resolve(&addr).unwrap_or(Address::Tcp(vec![]));
+        let matches = |server: &Server| server.name == name || server.is_at(&address);
+        let mut pool = self.pool.write()?;
+        // Same locking order as `eject` and `restore_ejected`.
+        let mut ejected = self.ejected.lock()?;
+        ejected.retain(|e| !matches(&e.server));
+        self.schedule_probe(&ejected);
+        let mut distributor = self.distributor.write()?;
+        let mut removed = false;
+        pool.retain(|server| {
+            if !matches(server) {
+                return true;
+            }
+            distributor.remove(&server.name);
+            removed = true;
+            false
+        });
+        Ok(removed)
+    }
+
+    /// Get the server responsible for `key`.
+    fn node(&self, key: &[u8]) -> Result<Arc<Server>> {
+        // Strictly after, since `next_probe` is rounded down.
+        if self.ejection.is_some()
+            && self.millis(Instant::now()) > self.next_probe.load(Ordering::Acquire)
+        {
+            self.restore_ejected()?;
+        }
+        let name = match self.distributor.read()?.get(key) {
+            Some(name) => name.to_string(),
+            None => bail!(ErrorKind::NoServers),
+        };
+        let pool = self.pool.read()?;
+        match pool.iter().find(|server| server.name == name) {
+            Some(server) => Ok(server.clone()),
+            // Removed since it was picked.
+            None => bail!(ErrorKind::NoServers),
+        }
+    }
+
+    /// Run `f` on a connection to `server` keeping track of its failures.
+    fn with<T, F>(&self, server: &Arc<Server>, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut protocol::Protocol<Transport>) -> Result<T>,
+    {
+        let result = server.with(f);
+        match result {
+            Err(ref e) if breaks_connection(e) => {
+                let failures = server.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+                if let Some(ejection) = self.ejection {
+                    if failures >= ejection.failure_limit {
+                        self.eject(server, ejection)?;
+                    }
+                }
             }
+            _ => server.consecutive_failures.store(0, Ordering::SeqCst),
+        }
+        result
+    }
+
+    fn eject(&self, server: &Arc<Server>, ejection: Ejection) -> Result<()> {
+        let pool = self.pool.read()?;
+        let mut ejected = self.ejected.lock()?;
+        let removed = !pool.iter().any(|s| Arc::ptr_eq(s, server));
+        let already_ejected = ejected.iter().any(|e| Arc::ptr_eq(&e.server, server));
+        if removed || already_ejected {
+            return Ok(());
+        }
+        warn!("Ejecting {} from the hash ring", server.name);
+        self.distributor.write()?.remove(&server.name);
+        ejected.push(EjectedServer {
+            server: server.clone(),
+            retry_at: Instant::now() + ejection.retry_interval,
+        });
+        self.schedule_probe(&ejected);
+        Ok(())
+    }
+
+    /// Milliseconds between the creation of the client and `instant`.
+    fn millis(&self, instant: Instant) -> u64 {
+        instant.saturating_duration_since(self.created).as_millis() as u64
+    }
+
+    /// Remember when the first of the `ejected` servers is due to be probed.
+    fn schedule_probe(&self, ejected: &[EjectedServer]) {
+        let next_probe = ejected
+            .iter()
+            .map(|e| self.millis(e.retry_at))
+            .min()
+            .unwrap_or(u64::MAX);
+        self.next_probe.store(next_probe, Ordering::Release);
+    }
+
+    /// Put back in the hash ring the ejected servers that answer again.
+    fn restore_ejected(&self) -> Result<()> {
+        let now = Instant::now();
+        let due: Vec<Arc<Server>> = {
+            let mut ejected = self.ejected.lock()?;
+            let retry_interval = match self.ejection {
+                Some(ejection) => ejection.retry_interval,
+                None => return Ok(()),
+            };
+            let due = ejected
+                .iter_mut()
+                .filter(|e| e.retry_at <= now)
+                .map(|e| {
+                    // Make sure other threads don't probe the same server at the same time.
+                    e.retry_at = now + retry_interval;
+                    e.server.clone()
+                })
+                .collect();
+            self.schedule_probe(&ejected);
+            due
+        };
+        for server in due {
+            if let Err(e) = server.probe() {
+                debug!("{} is still failing: {}", server.name, e);
+                continue;
+            }
+            info!("Putting {} back in the hash ring", server.name);
+            server.consecutive_failures.store(0, Ordering::SeqCst);
+            let mut ejected = self.ejected.lock()?;
+            let len = ejected.len();
+            ejected.retain(|e| !Arc::ptr_eq(&e.server, &server));
+            self.schedule_probe(&ejected);
+            // The server may have been removed from the pool while it was probed.
+            if ejected.len() < len {
+                self.distributor.write()?.add(&server.name, server.weight);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `f` once for each server, no matter how many connections there are to it, and return
+    /// its results keyed by the server address. Servers ejected from the hash ring are skipped
+    /// unless `with_ejected` is set. A failing server doesn't stop the others, the first error is
+    /// returned once they are done.
+    fn for_each_server<T, F>(&self, with_ejected: bool, mut f: F) -> Result<HashMap<String, T>>
+    where
+        F: FnMut(&mut protocol::Protocol<Transport>) -> Result<T>,
+    {
+        let pool = self.pool.read()?.clone();
+        let ejected: Vec<Arc<Server>> = if with_ejected {
+            vec![]
+        } else {
+            self.ejected
+                .lock()?
+                .iter()
+                .map(|e| e.server.clone())
+                .collect()
+        };
+        let mut results = HashMap::new();
+        let mut error = None;
+        for server in &pool {
+            if ejected.iter().any(|e| Arc::ptr_eq(e, server)) {
+                continue;
+            }
+            match self.with(server, |protocol| f(protocol)) {
+                Ok(result) => {
+                    results.insert(server.name.clone(), result);
+                }
+                Err(e) => {
+                    warn!("Request to {} failed: {}", server.name, e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(results),
+        }
+    }
+
+    /// Invalidate all items on every server, after `delay` seconds if it is not 0. Servers ejected
+    /// from the hash ring are flushed too, so they don't serve stale items once they are back, and
+    /// this fails if any of them can't be reached.
+    pub fn flush_all(&self, delay: u32) -> Result<()> {
+        self.for_each_server(true, |protocol| protocol.flush(delay))?;
+        Ok(())
+    }
+
+    /// Get the version of every server keyed by their address.
+    pub fn version(&self) -> Result<HashMap<String, String>> {
+        self.for_each_server(false, |protocol| protocol.version())
+    }
+
+    /// Send a `NoOp` to every server of the hash ring to make sure they are all reachable.
+    pub fn noop(&self) -> Result<()> {
+        self.for_each_server(false, |protocol| protocol.noop())?;
+        Ok(())
+    }
+
+    /// Set the verbosity level of every server.
+    pub fn verbosity(&self, level: u32) -> Result<()> {
+        self.for_each_server(false, |protocol| protocol.verbosity(level))?;
+        Ok(())
+    }
+
+    /// Get the raw statistics of every server keyed by their address. Check `Protocol::stats` for
+    /// the meaning of `group`.
+    pub fn stats(&self, group: Option<&str>) -> Result<HashMap<String, HashMap<String, String>>> {
+        self.for_each_server(false, |protocol| protocol.stats(group))
+    }
+
+    /// Get the general statistics of every server keyed by their address.
+    pub fn server_stats(&self) -> Result<HashMap<String, ServerStats>> {
+        self.for_each_server(false, |protocol| {
+            ServerStats::from_stats(&protocol.stats(None)?)
+        })
+    }
+
+    /// Politely close every connection of the pool. Connections that are already broken are not
+    /// reopened, and a failure doesn't stop the other connections from being closed, the first
+    /// error is returned once they all are.
+    pub fn quit(self) -> Result<()> {
+        let mut error = None;
+        for server in self.pool.read()?.iter() {
+            for connection in &server.connections {
+                let mut connection = lock(connection);
+                if let Some(mut protocol) = connection.protocol.take() {
+                    if let Err(e) = protocol.quit() {
+                        warn!("Could not close connection to {}: {}", connection.name, e);
+                        error.get_or_insert(e);
+                    }
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
-        Ok(MemcachedClient { connections: ch })
     }
 
     pub fn set<K, V>(&self, key: K, value: V, time: u32) -> Result<()>
@@ -47,9 +791,17 @@ impl MemcachedClient {
         K: AsRef<[u8]>,
         V: protocol::ToMemcached,
     {
-        let clonable_protocol = self.connections.get(key.as_ref()).unwrap();
-        let mut protocol = clonable_protocol.connection.lock().unwrap();
-        protocol.set(key, value, time)
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.set(key, value, time))
+    }
+
+    pub fn set_cas<K, V>(&self, key: K, value: V, time: u32, cas: u64) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+        V: protocol::ToMemcached,
+    {
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.set_cas(key, value, time, cas))
     }
 
     pub fn add<K, V>(&self, key: K, value: V, time: u32) -> Result<()>
@@ -57,9 +809,8 @@ impl MemcachedClient {
         K: AsRef<[u8]>,
         V: protocol::ToMemcached,
     {
-        let clonable_protocol = self.connections.get(key.as_ref()).unwrap();
-        let mut protocol = clonable_protocol.connection.lock().unwrap();
-        protocol.add(key, value, time)
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.add(key, value, time))
     }
 
     pub fn replace<K, V>(&self, key: K, value: V, time: u32) -> Result<()>
@@ -67,9 +818,88 @@ impl MemcachedClient {
         K: AsRef<[u8]>,
         V: protocol::ToMemcached,
     {
-        let clonable_protocol = self.connections.get(key.as_ref()).unwrap();
-        let mut protocol = clonable_protocol.connection.lock().unwrap();
-        protocol.replace(key, value, time)
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.replace(key, value, time))
+    }
+
+    pub fn replace_cas<K, V>(&self, key: K, value: V, time: u32, cas: u64) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+        V: protocol::ToMemcached,
+    {
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| {
+            protocol.replace_cas(key, value, time, cas)
+        })
+    }
+
+    /// Store all `values` using quiet commands, with a single round trip per server.
+    /// Returns the keys that could not be stored along with the status sent by the server, the
+    /// keys of a server that failed are only reported through `MultiResult::failed`.
+    pub fn set_multi<K, V>(
+        &self,
+        values: &[(K, V)],
+        time: u32,
+    ) -> Result<MultiResult<HashMap<K, Status>>>
+    where
+        K: AsRef<[u8]> + Eq + Hash + Clone,
+        V: protocol::ToMemcached,
+    {
+        self.multi(
+            values,
+            |v| v.0.as_ref(),
+            |protocol, group| protocol.set_multi(group, time),
+        )
+    }
+
+    pub fn add_multi<K, V>(
+        &self,
+        values: &[(K, V)],
+        time: u32,
+    ) -> Result<MultiResult<HashMap<K, Status>>>
+    where
+        K: AsRef<[u8]> + Eq + Hash + Clone,
+        V: protocol::ToMemcached,
+    {
+        self.multi(
+            values,
+            |v| v.0.as_ref(),
+            |protocol, group| protocol.add_multi(group, time),
+        )
+    }
+
+    pub fn replace_multi<K, V>(
+        &self,
+        values: &[(K, V)],
+        time: u32,
+    ) -> Result<MultiResult<HashMap<K, Status>>>
+    where
+        K: AsRef<[u8]> + Eq + Hash + Clone,
+        V: protocol::ToMemcached,
+    {
+        self.multi(
+            values,
+            |v| v.0.as_ref(),
+            |protocol, group| protocol.replace_multi(group, time),
+        )
+    }
+
+    pub fn append<K, V>(&self, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: protocol::ToMemcached,
+    {
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.append(key, value))
+    }
+
+    pub fn prepend<K, V>(&self, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: protocol::ToMemcached,
+    {
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.prepend(key, value))
     }
 
     pub fn get<K, V>(&self, key: K) -> Result<V>
@@ -77,47 +907,174 @@ impl MemcachedClient {
         K: AsRef<[u8]>,
         V: protocol::FromMemcached,
     {
-        let clonable_protocol = self.connections.get(key.as_ref()).unwrap();
-        let mut protocol = clonable_protocol.connection.lock().unwrap();
-        protocol.get(key)
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.get(key))
     }
 
-    pub fn delete<K>(&self, key: K) -> Result<()>
+    pub fn gets<K, V>(&self, key: K) -> Result<(V, u64)>
     where
         K: AsRef<[u8]>,
+        V: protocol::FromMemcached,
     {
-        let clonable_protocol = self.connections.get(key.as_ref()).unwrap();
-        let mut protocol = clonable_protocol.connection.lock().unwrap();
-        protocol.delete(key)
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.gets(key))
+    }
+
+    /// Get an item with its flags as they are stored, whatever library stored it.
+    pub fn get_raw<K: AsRef<[u8]>>(&self, key: K) -> Result<protocol::RawItem> {
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.get_raw(key))
     }
 
-    pub fn increment<K>(
+    /// Store `value` with any `flags`, check `Protocol::set_raw`.
+    pub fn set_raw<K: AsRef<[u8]>>(
         &self,
         key: K,
-        amount: u64,
-        initial: u64,
+        value: &[u8],
+        flags: u32,
         time: u32,
-    ) -> Result<u64>
+        cas: u64,
+    ) -> Result<u64> {
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| {
+            protocol.set_raw(key, value, flags, time, cas)
+        })
+    }
+
+    /// Split `items` by the server responsible for the key of each one of them.
+    fn group_by_server<'a, T, F>(
+        &self,
+        items: &'a [T],
+        key_of: F,
+    ) -> Result<Vec<(Arc<Server>, Vec<&'a T>)>>
+    where
+        F: Fn(&'a T) -> &'a [u8],
+    {
+        let mut groups: Vec<(Arc<Server>, Vec<&'a T>)> = vec![];
+        for item in items {
+            let server = self.node(key_of(item))?;
+            match groups.iter_mut().find(|(s, _)| Arc::ptr_eq(s, &server)) {
+                Some((_, group)) => group.push(item),
+                None => groups.push((server, vec![item])),
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Run `f` on every server with its share of `items`, merging what the servers that succeed
+    /// return and keeping the errors of the others.
+    fn multi<'a, T, R, K, F>(&self, items: &'a [T], key_of: K, f: F) -> Result<MultiResult<R>>
+    where
+        R: Default + IntoIterator + Extend<<R as IntoIterator>::Item>,
+        K: Fn(&'a T) -> &'a [u8],
+        F: Fn(&mut protocol::Protocol<Transport>, Vec<&'a T>) -> Result<R>,
+    {
+        let mut result = MultiResult {
+            values: R::default(),
+            failed: vec![],
+        };
+        for (server, group) in self.group_by_server(items, key_of)? {
+            match self.with(&server, |protocol| f(protocol, group)) {
+                Ok(values) => result.values.extend(values),
+                Err(e) => result.failed.push((server.name.clone(), e)),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Get multiple keys at once, grouping them by server so each one gets a single round trip.
+    /// Keys that are not found are not present in the returned map, like the keys of the servers
+    /// listed in `MultiResult::failed`.
+    pub fn get_multi<K, V>(&self, keys: &[K]) -> Result<MultiResult<HashMap<K, V>>>
+    where
+        K: AsRef<[u8]> + Eq + Hash + Clone,
+        V: protocol::FromMemcached,
+    {
+        self.multi(
+            keys,
+            |key| key.as_ref(),
+            |protocol, group| protocol.get_multi(group),
+        )
+    }
+
+    pub fn get_and_touch<K, V>(&self, key: K, time: u32) -> Result<V>
     where
         K: AsRef<[u8]>,
+        V: protocol::FromMemcached,
     {
-        let clonable_protocol = self.connections.get(key.as_ref()).unwrap();
-        let mut protocol = clonable_protocol.connection.lock().unwrap();
-        protocol.increment(key, amount, initial, time)
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.get_and_touch(key, time))
     }
 
-    pub fn decrement<K>(
+    pub fn get_and_touch_multi<K, V>(
         &self,
-        key: K,
-        amount: u64,
-        initial: u64,
+        keys: &[K],
         time: u32,
-    ) -> Result<u64>
+    ) -> Result<MultiResult<HashMap<K, V>>>
+    where
+        K: AsRef<[u8]> + Eq + Hash + Clone,
+        V: protocol::FromMemcached,
+    {
+        self.multi(
+            keys,
+            |key| key.as_ref(),
+            |protocol, group| protocol.get_and_touch_multi(group, time),
+        )
+    }
+
+    pub fn touch<K>(&self, key: K, time: u32) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+    {
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.touch(key, time))
+    }
+
+    pub fn delete<K>(&self, key: K) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+    {
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.delete(key))
+    }
+
+    pub fn delete_cas<K>(&self, key: K, cas: u64) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+    {
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| protocol.delete_cas(key, cas))
+    }
+
+    /// Delete all `keys` using quiet commands, with a single round trip per server.
+    pub fn delete_multi<K>(&self, keys: &[K]) -> Result<MultiResult<HashMap<K, Status>>>
+    where
+        K: AsRef<[u8]> + Eq + Hash + Clone,
+    {
+        self.multi(
+            keys,
+            |key| key.as_ref(),
+            |protocol, group| protocol.delete_multi(group),
+        )
+    }
+
+    pub fn increment<K>(&self, key: K, amount: u64, initial: u64, time: u32) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+    {
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| {
+            protocol.increment(key, amount, initial, time)
+        })
+    }
+
+    pub fn decrement<K>(&self, key: K, amount: u64, initial: u64, time: u32) -> Result<u64>
     where
         K: AsRef<[u8]>,
     {
-        let clonable_protocol = self.connections.get(key.as_ref()).unwrap();
-        let mut protocol = clonable_protocol.connection.lock().unwrap();
-        protocol.decrement(key, amount, initial, time)
+        let server = self.node(key.as_ref())?;
+        self.with(&server, |protocol| {
+            protocol.decrement(key, amount, initial, time)
+        })
     }
 }
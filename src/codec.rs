@@ -0,0 +1,424 @@
+//! Encoding of requests and decoding of responses of the binary protocol, without any I/O, to
+//! embed memcached traffic in other event loops or proxies. With the `codec` feature,
+//! `MemcachedCodec` does the same for `tokio_util::codec::Framed`.
+use std::io::Write;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+#[cfg(feature = "codec")]
+use bytes::{Buf, BytesMut};
+use enum_primitive::FromPrimitive;
+#[cfg(feature = "codec")]
+use tokio_util::codec::{Decoder, Encoder};
+
+#[cfg(feature = "codec")]
+use crate::errors::Error;
+use crate::errors::{ErrorKind, Result};
+use crate::protocol::{Status, KEY_MAXIMUM_SIZE};
+
+/// Every request and response starts with a header of this length.
+pub const HEADER_LENGTH: usize = 24;
+
+pub(crate) enum Type {
+    Request = 0x80,
+    Response = 0x81,
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    Get = 0x00,
+    Set = 0x01,
+    Add = 0x02,
+    Replace = 0x03,
+    Delete = 0x04,
+    Increment = 0x05,
+    Decrement = 0x06,
+    Quit = 0x07,
+    Flush = 0x08,
+    GetQ = 0x09,
+    NoOp = 0x0A,
+    Version = 0x0B,
+    GetK = 0x0C,
+    GetKQ = 0x0D,
+    Append = 0x0E,
+    Prepend = 0x0F,
+    Stat = 0x10,
+    SetQ = 0x11,
+    AddQ = 0x12,
+    ReplaceQ = 0x13,
+    DeleteQ = 0x14,
+    IncrementQ = 0x15,
+    DecrementQ = 0x16,
+    QuitQ = 0x17,
+    FlushQ = 0x18,
+    AppendQ = 0x19,
+    PrependQ = 0x1A,
+    Verbosity = 0x1B,
+    Touch = 0x1C,
+    GetAndTouch = 0x1D,
+    GetAndTouchQ = 0x1E,
+    SaslListMechs = 0x20,
+    SaslAuth = 0x21,
+    SaslStep = 0x22,
+}
+
+/// Header of a request, which is followed by its extras, key and value.
+#[derive(Debug)]
+pub struct Request {
+    magic: u8,
+    pub(crate) opcode: u8,
+    key_length: u16,
+    extras_length: u8,
+    data_type: u8,
+    reserved: u16,
+    body_length: u32,
+    pub(crate) opaque: u32,
+    cas: u64,
+}
+
+impl Request {
+    pub(crate) fn new(
+        command: Command,
+        key_length: usize,
+        value_length: usize,
+        data_type: u8,
+        extras_length: usize,
+        cas: u64,
+    ) -> Result<Request> {
+        Request::with_opcode(
+            command as u8,
+            key_length,
+            value_length,
+            data_type,
+            extras_length,
+            cas,
+        )
+    }
+
+    fn with_opcode(
+        opcode: u8,
+        key_length: usize,
+        value_length: usize,
+        data_type: u8,
+        extras_length: usize,
+        cas: u64,
+    ) -> Result<Request> {
+        if key_length > KEY_MAXIMUM_SIZE {
+            bail!(ErrorKind::KeyLengthTooLong(key_length));
+        }
+        if extras_length > u8::MAX as usize {
+            bail!("Extras of {} bytes are too long", extras_length);
+        }
+        Ok(Request {
+            magic: Type::Request as u8,
+            opcode,
+            key_length: key_length as u16,
+            extras_length: extras_length as u8,
+            data_type,
+            reserved: 0,
+            body_length: (key_length + value_length + extras_length) as u32,
+            opaque: 0,
+            cas,
+        })
+    }
+
+    /// Write the header followed by `final_payload`, which holds the extras, key and value.
+    pub(crate) fn write_to<W: Write>(&self, buf: &mut W, final_payload: &[u8]) -> Result<()> {
+        buf.write_u8(self.magic)?;
+        buf.write_u8(self.opcode)?;
+        buf.write_u16::<BigEndian>(self.key_length)?;
+        buf.write_u8(self.extras_length)?;
+        buf.write_u8(self.data_type)?;
+        buf.write_u16::<BigEndian>(self.reserved)?;
+        buf.write_u32::<BigEndian>(self.body_length)?;
+        buf.write_u32::<BigEndian>(self.opaque)?;
+        buf.write_u64::<BigEndian>(self.cas)?;
+        buf.write_all(final_payload)?;
+        Ok(())
+    }
+}
+
+/// Header of a response, which is followed by its extras, key and value.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct Response {
+    magic: u8,
+    pub(crate) opcode: u8,
+    pub(crate) key_length: u16,
+    pub(crate) extras_length: u8,
+    data_type: u8,
+    pub(crate) status: u16,
+    pub(crate) body_length: u32,
+    pub(crate) opaque: u32,
+    pub(crate) cas: u64,
+}
+
+impl Response {
+    /// Parse a response header, or explain why it can't be one.
+    pub(crate) fn parse(header: &[u8; HEADER_LENGTH]) -> ::std::result::Result<Response, String> {
+        if header[0] != Type::Response as u8 {
+            return Err(format!("unknown magic code 0x{:02x}", header[0]));
+        }
+        let response = Response {
+            magic: header[0],
+            opcode: header[1],
+            key_length: BigEndian::read_u16(&header[2..4]),
+            extras_length: header[4],
+            data_type: header[5],
+            status: BigEndian::read_u16(&header[6..8]),
+            body_length: BigEndian::read_u32(&header[8..12]),
+            opaque: BigEndian::read_u32(&header[12..16]),
+            cas: BigEndian::read_u64(&header[16..24]),
+        };
+        if u32::from(response.key_length) + u32::from(response.extras_length) > response.body_length
+        {
+            return Err(format!(
+                "body length {} is shorter than the key and extras",
+                response.body_length
+            ));
+        }
+        Ok(response)
+    }
+}
+
+/// A whole request. The opcode is usually a `Command`, but any other one can be sent.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestFrame {
+    pub opcode: u8,
+    pub opaque: u32,
+    pub cas: u64,
+    pub extras: Vec<u8>,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl RequestFrame {
+    pub fn new(command: Command) -> RequestFrame {
+        RequestFrame {
+            opcode: command as u8,
+            ..RequestFrame::default()
+        }
+    }
+
+    /// Append the request to `buf`. Fails if the key or the extras are too long.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let mut request = Request::with_opcode(
+            self.opcode,
+            self.key.len(),
+            self.value.len(),
+            0x00,
+            self.extras.len(),
+            self.cas,
+        )?;
+        request.opaque = self.opaque;
+        buf.reserve(HEADER_LENGTH + request.body_length as usize);
+        request.write_to(buf, &self.extras)?;
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&self.value);
+        Ok(())
+    }
+}
+
+/// A whole response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseFrame {
+    pub opcode: u8,
+    pub data_type: u8,
+    pub status: u16,
+    pub opaque: u32,
+    pub cas: u64,
+    pub extras: Vec<u8>,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl ResponseFrame {
+    /// Split `body` in the parts described by `header`.
+    pub(crate) fn from_parts(header: Response, mut body: Vec<u8>) -> ResponseFrame {
+        let extras_length = header.extras_length as usize;
+        let value = body.split_off(extras_length + header.key_length as usize);
+        let key = body.split_off(extras_length);
+        ResponseFrame {
+            opcode: header.opcode,
+            data_type: header.data_type,
+            status: header.status,
+            opaque: header.opaque,
+            cas: header.cas,
+            extras: body,
+            key,
+            value,
+        }
+    }
+
+    /// The status of the response, `None` if this crate doesn't know it.
+    pub fn known_status(&self) -> Option<Status> {
+        Status::from_u16(self.status)
+    }
+}
+
+/// Decode the response at the start of `buf`, returning it along with the number of bytes it
+/// took. Returns `None` until `buf` holds the whole response. Fails with
+/// `ErrorKind::Desynchronized` when `buf` doesn't start with a response, as there is no way to
+/// find where the next one starts.
+pub fn decode_response(buf: &[u8]) -> Result<Option<(ResponseFrame, usize)>> {
+    if buf.len() < HEADER_LENGTH {
+        return Ok(None);
+    }
+    let mut header = [0; HEADER_LENGTH];
+    header.copy_from_slice(&buf[..HEADER_LENGTH]);
+    let response = Response::parse(&header).map_err(ErrorKind::Desynchronized)?;
+    let length = HEADER_LENGTH + response.body_length as usize;
+    if buf.len() < length {
+        return Ok(None);
+    }
+    let body = buf[HEADER_LENGTH..length].to_vec();
+    Ok(Some((ResponseFrame::from_parts(response, body), length)))
+}
+
+/// Encodes `RequestFrame`s and decodes `ResponseFrame`s for `tokio_util::codec::Framed`.
+#[cfg(feature = "codec")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemcachedCodec;
+
+#[cfg(feature = "codec")]
+impl Encoder<RequestFrame> for MemcachedCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: RequestFrame, dst: &mut BytesMut) -> Result<()> {
+        let mut buf = Vec::new();
+        item.encode(&mut buf)?;
+        dst.extend_from_slice(&buf);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "codec")]
+impl Decoder for MemcachedCodec {
+    type Item = ResponseFrame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ResponseFrame>> {
+        match decode_response(src)? {
+            Some((frame, length)) => {
+                src.advance(length);
+                Ok(Some(frame))
+            }
+            None => {
+                // Make room for the rest of the response once its length is known.
+                if src.len() >= HEADER_LENGTH {
+                    let length = HEADER_LENGTH + BigEndian::read_u32(&src[8..12]) as usize;
+                    src.reserve(length - src.len());
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Error;
+
+    /// A response to a `Get`, with the flags as extras.
+    fn get_response(opaque: u32, value: &[u8]) -> Vec<u8> {
+        let mut buf = vec![Type::Response as u8, Command::Get as u8, 0, 0, 4, 0, 0, 0];
+        buf.write_u32::<BigEndian>(4 + value.len() as u32).unwrap();
+        buf.write_u32::<BigEndian>(opaque).unwrap();
+        buf.write_u64::<BigEndian>(42).unwrap();
+        buf.write_u32::<BigEndian>(1).unwrap();
+        buf.extend_from_slice(value);
+        buf
+    }
+
+    #[test]
+    fn encode_request() {
+        let request = RequestFrame {
+            opaque: 7,
+            extras: vec![0, 0, 0, 1, 0, 0, 0, 100],
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+            ..RequestFrame::new(Command::Set)
+        };
+        let mut buf = vec![];
+        request.encode(&mut buf).unwrap();
+        let mut expected = vec![0x80, 0x01, 0, 3, 8, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 7];
+        expected.extend_from_slice(&[0; 8]);
+        expected.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 100]);
+        expected.extend_from_slice(b"keyvalue");
+        assert_eq!(buf, expected);
+
+        let request = RequestFrame {
+            key: vec![b'a'; KEY_MAXIMUM_SIZE + 1],
+            ..RequestFrame::new(Command::Get)
+        };
+        match request.encode(&mut buf) {
+            Err(Error(ErrorKind::KeyLengthTooLong(_), _)) => {}
+            e => panic!("Encoding should fail and not {:?}", e),
+        }
+    }
+
+    #[test]
+    fn decode_incrementally() {
+        let mut buf = get_response(3, b"World");
+        buf.extend(get_response(4, b"Again"));
+        let length = HEADER_LENGTH + 4 + 5;
+        for end in 0..length {
+            assert_eq!(decode_response(&buf[..end]).unwrap(), None);
+        }
+        let (frame, used) = decode_response(&buf).unwrap().unwrap();
+        assert_eq!(used, length);
+        assert_eq!(
+            frame,
+            ResponseFrame {
+                opcode: Command::Get as u8,
+                data_type: 0,
+                status: 0,
+                opaque: 3,
+                cas: 42,
+                extras: vec![0, 0, 0, 1],
+                key: vec![],
+                value: b"World".to_vec(),
+            }
+        );
+        assert_eq!(frame.known_status(), Some(Status::Success));
+        let (frame, used) = decode_response(&buf[used..]).unwrap().unwrap();
+        assert_eq!(used, length);
+        assert_eq!(frame.opaque, 4);
+        assert_eq!(frame.value, b"Again");
+    }
+
+    #[test]
+    fn decode_garbage() {
+        match decode_response(&[0x42; HEADER_LENGTH]) {
+            Err(Error(ErrorKind::Desynchronized(_), _)) => {}
+            e => panic!("Decoding should fail and not {:?}", e),
+        }
+        // A body too short for the key and extras it should have.
+        let mut buf = get_response(1, b"");
+        buf[11] = 2;
+        match decode_response(&buf) {
+            Err(Error(ErrorKind::Desynchronized(_), _)) => {}
+            e => panic!("Decoding should fail and not {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "codec")]
+    #[test]
+    fn tokio_codec() {
+        let mut codec = MemcachedCodec;
+        let mut buf = BytesMut::new();
+        codec
+            .encode(RequestFrame::new(Command::NoOp), &mut buf)
+            .unwrap();
+        assert_eq!(buf.len(), HEADER_LENGTH);
+
+        let response = get_response(9, b"World");
+        let mut buf = BytesMut::from(&response[..30]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&response[30..]);
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.opaque, 9);
+        assert_eq!(frame.value, b"World");
+        assert!(buf.is_empty());
+    }
+}
@@ -1,13 +1,27 @@
-use constants::StoredType;
-use protocol::{Status, KEY_MAXIMUM_SIZE};
+use std::io;
+use std::sync::PoisonError;
+
+use crate::constants::StoredType;
+use crate::protocol::{Status, KEY_MAXIMUM_SIZE};
 
 error_chain! {
     foreign_links {
-        IoError(::std::io::Error);
         Utf8Error(::std::string::FromUtf8Error);
     }
 
     errors {
+        // Not a foreign link so timeouts can be converted to their own kind, check `From` below.
+        IoError(e: io::Error) {
+            description("I/O error")
+            display("{}", e)
+        }
+
+        /// The server did not answer in time. Callers may want to treat this as a cache miss.
+        Timeout {
+            description("Timed out waiting for the server")
+            display("Timed out waiting for the server")
+        }
+
         Status(s: Status) {
             description("Invalid status received")
             display("Invalid status received {:?}", s)
@@ -19,9 +33,73 @@ error_chain! {
             display("Requested type is different from the one stored in memcached: {:?}", s)
         }
 
+        /// Returned by the `*_cas` operations when the item was changed since its CAS token
+        /// was read.
+        CasMismatch {
+            description("CAS token does not match the stored item")
+            display("CAS token does not match the stored item")
+        }
+
+        /// The connection to the server broke and reconnecting failed recently, so it will only be
+        /// retried after a while.
+        Disconnected(server: String) {
+            description("Not connected to the server")
+            display("Not connected to {}, waiting to reconnect", server)
+        }
+
+        /// The flags of the item are not a `StoredType`, usually because it was stored by another
+        /// client library.
+        UnknownFlags(flags: u32) {
+            description("Unknown flags")
+            display("Unknown flags 0x{:08x}, the item was not stored by this crate", flags)
+        }
+
+        /// A thread panicked while changing the list of servers, so it can't be trusted anymore.
+        Poisoned {
+            description("Client state is poisoned")
+            display("A thread panicked while changing the servers of the client")
+        }
+
+        /// There is no server to send requests to, they were all removed or ejected.
+        NoServers {
+            description("No server available")
+            display("No server available")
+        }
+
+        /// The server rejected the credentials, with the message it sent.
+        AuthenticationFailed(message: String) {
+            description("Authentication failed")
+            display("Authentication failed: {}", message)
+        }
+
+        /// The server sent something that doesn't answer the request, so the following responses
+        /// can't be trusted either. The connection is closed.
+        Desynchronized(reason: String) {
+            description("Connection out of sync with the server")
+            display("Connection out of sync with the server: {}", reason)
+        }
+
         KeyLengthTooLong(length: usize) {
             description("Key length is too long")
             display("Key length {} is too long, the maximum is {}", length, KEY_MAXIMUM_SIZE)
         }
     }
 }
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        match e.kind() {
+            // Unix platforms report read and write timeouts as WouldBlock.
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                Error::with_chain(e, ErrorKind::Timeout)
+            }
+            _ => ErrorKind::IoError(e).into(),
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_: PoisonError<T>) -> Error {
+        ErrorKind::Poisoned.into()
+    }
+}
@@ -47,21 +47,32 @@ fn main() {
 #[macro_use]
 extern crate bitflags;
 extern crate byteorder;
-extern crate conhash;
 #[macro_use]
 extern crate enum_primitive;
 #[macro_use]
 extern crate log;
+extern crate md5;
 extern crate num;
 
+#[cfg(feature = "tokio")]
+pub mod async_client;
 mod client;
+pub mod codec;
 pub mod constants;
+pub mod distribution;
 pub mod errors;
 pub mod protocol;
+pub mod stats;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod transport;
 
-pub use protocol::{FromMemcached, Status, ToMemcached};
+pub use protocol::{FromMemcached, RawItem, Status, ToMemcached};
+pub use stats::ServerStats;
 #[macro_use]
 extern crate error_chain;
 
-pub use client::MemcachedClient;
+#[cfg(feature = "tokio")]
+pub use async_client::{AsyncMemcachedClient, AsyncMemcachedClientBuilder, AsyncProtocol};
+pub use client::{MemcachedClient, MemcachedClientBuilder, MultiResult};
 pub use constants::StoredType;
@@ -1,50 +1,28 @@
+use std::collections::HashMap;
+use std::hash::Hash;
 use std::io::{BufReader, BufWriter, Cursor, Read, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::Duration;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use enum_primitive::FromPrimitive;
 
-use constants::*;
-use errors::{ErrorKind, Result};
+use crate::codec::Command;
+pub use crate::codec::{Request, Response};
+use crate::constants::*;
+use crate::errors::{Error, ErrorKind, Result};
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsStream};
+use crate::transport;
 
 pub const KEY_MAXIMUM_SIZE: usize = 250;
 
-enum Type {
-    Request = 0x80,
-    Response = 0x81,
-}
-
-#[derive(Debug)]
-enum Command {
-    Get = 0x00,
-    Set = 0x01,
-    Add = 0x02,
-    Replace = 0x03,
-    Delete = 0x04,
-    Increment = 0x05,
-    Decrement = 0x06,
-    // Quit = 0x07,
-    // Flush = 0x08,
-    // GetQ = 0x09,
-    // NoOp = 0x0A,
-    // Version = 0x0B,
-    // GetK = 0x0C,
-    // GetKQ = 0x0D,
-    // Append = 0x0E,
-    // Prepend = 0x0F,
-    // Stat = 0x10,
-    // SetQ = 0x11,
-    // AddQ = 0x12,
-    // ReplaceQ = 0x13,
-    // DeleteQ = 0x14,
-    // IncrementQ = 0x15,
-    // DecrementQ = 0x16,
-    // QuitQ = 0x17,
-    // FlushQ = 0x18,
-    // AppendQ = 0x19,
-    // PrependQ = 0x1A
-}
-
 enum_from_primitive! {
     #[derive(Debug, PartialEq)]
     pub enum Status {
@@ -53,40 +31,52 @@ enum_from_primitive! {
         KeyExists = 0x02,
         ValueTooBig = 0x03,
         InvalidArguments = 0x04,
+        ItemNotStored = 0x05,
         AuthError = 0x08,
+        AuthContinue = 0x09,
+        // memcached uses these ones instead of the 0x08 and 0x09 from the protocol specification.
+        SaslAuthError = 0x20,
+        SaslAuthContinue = 0x21,
         UnknownCommand = 0x81
     }
 }
 
-#[derive(Debug)]
-pub struct Request {
-    magic: u8,
-    opcode: u8,
-    key_length: u16,
-    extras_length: u8,
-    data_type: u8,
-    reserved: u16,
-    body_length: u32,
-    opaque: u32,
-    cas: u64,
+/// Outcome of a SASL authentication step.
+#[derive(Debug, PartialEq)]
+pub enum SaslResponse {
+    Authenticated,
+    /// The server needs another step, with the challenge it sent.
+    Continue(Vec<u8>),
 }
 
-#[derive(Debug)]
-pub struct Response {
-    magic: u8,
-    opcode: u8,
-    key_length: u16,
-    extras_length: u8,
-    data_type: u8,
-    status: u16,
-    body_length: u32,
-    opaque: u32,
-    cas: u64,
+/// An item as stored in memcached, with flags that don't need to be a `StoredType`. Useful to
+/// share keys with other client libraries, which have their own conventions for the flags.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawItem {
+    pub value: Vec<u8>,
+    pub flags: u32,
+    pub cas: u64,
 }
 
+/// Timeouts used by a connection, `None` means waiting forever.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+
+/// A connection to a single server, over a TCP stream by default or any other `Read + Write`
+/// stream given to `Protocol::new`.
 #[derive(Debug)]
-pub struct Protocol {
-    connection: BufReader<TcpStream>,
+pub struct Protocol<S = TcpStream> {
+    connection: BufReader<S>,
+    /// Opaque of the last request sent, incremented with each one.
+    opaque: u32,
+    /// Opcode and opaque that the response to the last request must have.
+    expected: (u8, u32),
+    /// Why the connection got out of sync with the server, after which it can't be used anymore.
+    desynchronized: Option<String>,
 }
 
 pub trait ToMemcached {
@@ -99,118 +89,330 @@ pub trait FromMemcached: Sized {
 
 impl Protocol {
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Protocol> {
-        Ok(Protocol {
-            connection: BufReader::new(TcpStream::connect(addr)?),
-        })
+        Protocol::connect_with_timeouts(addr, Timeouts::default())
+    }
+
+    /// Connect to the server and apply `timeouts` to the connection. Requests that take too long
+    /// fail with `ErrorKind::Timeout`.
+    pub fn connect_with_timeouts<A: ToSocketAddrs>(
+        addr: A,
+        timeouts: Timeouts,
+    ) -> Result<Protocol> {
+        Ok(Protocol::new(transport::connect_tcp(addr, timeouts)?))
     }
 
-    pub fn connection_info(&self) -> String {
+    pub fn connection_info(&self) -> Result<String> {
         let connection = self.connection.get_ref();
-        connection.peer_addr().unwrap().to_string()
+        Ok(connection.peer_addr()?.to_string())
     }
+}
 
-    fn build_request(
-        command: Command,
-        key_length: usize,
-        value_length: usize,
-        data_type: u8,
-        extras_length: usize,
-        cas: u64,
-    ) -> Result<Request> {
-        if key_length > KEY_MAXIMUM_SIZE {
-            bail!(ErrorKind::KeyLengthTooLong(key_length));
-        }
-        Ok(Request {
-            magic: Type::Request as u8,
-            opcode: command as u8,
-            key_length: key_length as u16,
-            extras_length: extras_length as u8,
-            data_type: data_type,
-            reserved: 0,
-            body_length: (key_length + value_length + extras_length) as u32,
+#[cfg(unix)]
+impl Protocol<UnixStream> {
+    /// Connect to a server listening on the Unix domain socket at `path`, like memcached started
+    /// with `-s`.
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Protocol<UnixStream>> {
+        Protocol::connect_unix_with_timeouts(path, Timeouts::default())
+    }
+
+    /// Like `connect_unix`, applying the read and write `timeouts` to the connection.
+    pub fn connect_unix_with_timeouts<P: AsRef<Path>>(
+        path: P,
+        timeouts: Timeouts,
+    ) -> Result<Protocol<UnixStream>> {
+        Ok(Protocol::new(transport::connect_unix(path, timeouts)?))
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Protocol<TlsStream> {
+    /// Connect to the server over TLS, checking its certificate against `server_name`. Check
+    /// `tls::client_config` to build `config`.
+    pub fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<Protocol<TlsStream>> {
+        Protocol::connect_tls_with_timeouts(addr, server_name, config, Timeouts::default())
+    }
+
+    /// Like `connect_tls`, applying `timeouts` to the connection.
+    pub fn connect_tls_with_timeouts<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: Arc<rustls::ClientConfig>,
+        timeouts: Timeouts,
+    ) -> Result<Protocol<TlsStream>> {
+        Ok(Protocol::new(tls::connect(
+            addr,
+            server_name,
+            config,
+            timeouts,
+        )?))
+    }
+}
+
+impl<S: Read + Write> Protocol<S> {
+    /// Talk to a server over an already open `stream`. Timeouts, if any, have to be set on the
+    /// stream itself.
+    pub fn new(stream: S) -> Protocol<S> {
+        Protocol {
+            connection: BufReader::new(stream),
             opaque: 0,
-            cas: cas,
-        })
+            expected: (0, 0),
+            desynchronized: None,
+        }
+    }
+
+    fn next_opaque(&mut self) -> u32 {
+        self.opaque = self.opaque.wrapping_add(1);
+        self.opaque
+    }
+
+    /// Buffer the requests written to the connection, unless it is out of sync with the server.
+    fn writer(&mut self) -> Result<BufWriter<&mut S>> {
+        if let Some(ref reason) = self.desynchronized {
+            bail!(ErrorKind::Desynchronized(reason.clone()));
+        }
+        Ok(BufWriter::new(self.connection.get_mut()))
     }
 
-    fn write_request(&mut self, request: Request, final_payload: &[u8]) -> Result<()> {
-        let connection = self.connection.get_mut();
-        let mut buf = BufWriter::new(connection);
-        buf.write_u8(request.magic)?;
-        buf.write_u8(request.opcode)?;
-        buf.write_u16::<BigEndian>(request.key_length)?;
-        buf.write_u8(request.extras_length)?;
-        buf.write_u8(request.data_type)?;
-        buf.write_u16::<BigEndian>(request.reserved)?;
-        buf.write_u32::<BigEndian>(request.body_length)?;
-        buf.write_u32::<BigEndian>(request.opaque)?;
-        buf.write_u64::<BigEndian>(request.cas)?;
-        buf.write(final_payload)?;
+    fn write_request(&mut self, mut request: Request, final_payload: &[u8]) -> Result<()> {
+        request.opaque = self.next_opaque();
+        self.expected = (request.opcode, request.opaque);
+        let mut buf = self.writer()?;
+        request.write_to(&mut buf, final_payload)?;
         buf.flush()?;
         Ok(())
     }
 
+    /// Read the response to the last request sent with `write_request`.
     fn read_response(&mut self) -> Result<Response> {
-        let buf = &mut self.connection;
-        let magic: u8 = buf.read_u8()?;
-        if magic != Type::Response as u8 {
-            // TODO Consume the stream, disconnect or something?
-            debug!("Server sent an unknown magic code {:?}", magic);
-            bail!("Server sent an unknown magic code");
-        }
-        Ok(Response {
-            magic,
-            opcode: buf.read_u8()?,
-            key_length: buf.read_u16::<BigEndian>()?,
-            extras_length: buf.read_u8()?,
-            data_type: buf.read_u8()?,
-            status: buf.read_u16::<BigEndian>()?,
-            body_length: buf.read_u32::<BigEndian>()?,
-            opaque: buf.read_u32::<BigEndian>()?,
-            cas: buf.read_u64::<BigEndian>()?,
-        })
+        let response = self.read_header()?;
+        let (opcode, opaque) = self.expected;
+        if response.opcode != opcode || response.opaque != opaque {
+            return Err(self.desynchronized(format!(
+                "expected opcode 0x{:02x} and opaque {}, got opcode 0x{:02x} and opaque {}",
+                opcode, opaque, response.opcode, response.opaque
+            )));
+        }
+        Ok(response)
     }
 
+    /// Read the response to one of the requests sent with `write_pipeline`, whose opaques start at
+    /// `first_opaque`. Returns the index of the request,
+    /// or `None` for the `NoOp` that ends them.
+    fn read_quiet_response(
+        &mut self,
+        opcode: u8,
+        first_opaque: u32,
+        count: usize,
+    ) -> Result<(Option<usize>, Response)> {
+        let response = self.read_header()?;
+        let index = response.opaque.wrapping_sub(first_opaque) as usize;
+        if response.opcode == Command::NoOp as u8 && index == count {
+            Ok((None, response))
+        } else if response.opcode == opcode && index < count {
+            Ok((Some(index), response))
+        } else {
+            Err(self.desynchronized(format!(
+                "got opcode 0x{:02x} and opaque {} for a batch of {} requests",
+                response.opcode, response.opaque, count
+            )))
+        }
+    }
+
+    fn read_header(&mut self) -> Result<Response> {
+        let mut header = [0; 24];
+        self.connection.read_exact(&mut header)?;
+        Response::parse(&header).map_err(|reason| self.desynchronized(reason))
+    }
+
+    /// Give up on the connection, there is no way to know where the next response starts. Later
+    /// requests fail instead of reading responses meant for other requests.
+    fn desynchronized(&mut self, reason: String) -> Error {
+        warn!(
+            "Giving up on connection out of sync with the server: {}",
+            reason
+        );
+        self.desynchronized = Some(reason.clone());
+        ErrorKind::Desynchronized(reason).into()
+    }
+
+    /// Skip the rest of the response, which may be binary.
     fn consume_body(&mut self, size: u32) -> Result<()> {
-        debug!("Consuming body");
         let mut buf: Vec<u8> = vec![0; size as usize];
-        self.connection.read(&mut *buf)?;
-        let str_buf = String::from_utf8(buf)?;
-        debug!("Consumed body {:?}", str_buf);
+        self.connection.read_exact(&mut buf)?;
+        debug!("Consumed body {:?}", String::from_utf8_lossy(&buf));
         Ok(())
     }
 
-    fn set_add_replace<K, V>(&mut self, command: Command, key: K, value: V, time: u32) -> Result<()>
+    /// Read the flags of a get response, which are its extras.
+    fn read_flags(&mut self, response: &Response) -> Result<u32> {
+        if response.extras_length != 4 {
+            return Err(self.desynchronized(format!(
+                "expected 4 bytes of flags, got {}",
+                response.extras_length
+            )));
+        }
+        Ok(self.connection.read_u32::<BigEndian>()?)
+    }
+
+    /// Skip the body of a response with a status this crate doesn't know about.
+    fn unknown_status(&mut self, response: &Response) -> Error {
+        if let Err(e) = self.consume_body(response.body_length) {
+            return e;
+        }
+        format!(
+            "Server sent an unknown status code 0x{:02x}",
+            response.status
+        )
+        .into()
+    }
+
+    fn set_add_replace<K, V>(
+        &mut self,
+        command: Command,
+        key: K,
+        value: V,
+        time: u32,
+        cas: u64,
+    ) -> Result<u64>
     where
         K: AsRef<[u8]>,
         V: ToMemcached,
     {
-        let key = key.as_ref();
         let (value, flags) = value.get_value()?;
+        self.store(command, key.as_ref(), &value, flags.bits(), time, cas)
+    }
 
-        let extras_length = 8; // Flags: u32 and Expiration time: u32
-        let request =
-            Protocol::build_request(command, key.len(), value.len(), 0x00, extras_length, 0x00)?;
-        let mut final_payload = vec![];
-        // Flags
-        final_payload.write_u32::<BigEndian>(flags.bits())?;
-        final_payload.write_u32::<BigEndian>(time)?;
-        // After flags key and value
-        final_payload.write(key)?;
-        final_payload.write(&value)?;
+    fn store(
+        &mut self,
+        command: Command,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        time: u32,
+        cas: u64,
+    ) -> Result<u64> {
+        let (request, final_payload) = build_store_request(command, key, value, flags, time, cas)?;
         self.write_request(request, final_payload.as_slice())?;
         let response = self.read_response()?;
         match Status::from_u16(response.status) {
-            Some(Status::Success) => Ok(()),
+            Some(Status::Success) => {
+                self.consume_body(response.body_length)?;
+                Ok(response.cas)
+            }
+            Some(Status::KeyExists) if cas != 0x00 => {
+                self.consume_body(response.body_length)?;
+                bail!(ErrorKind::CasMismatch)
+            }
             Some(rest) => {
                 self.consume_body(response.body_length)?;
                 bail!(ErrorKind::Status(rest))
             }
-            None => bail!(
-                "Server returned an unknown status code 0x{:02x}",
-                response.status
-            ),
+            None => Err(self.unknown_status(&response)),
+        }
+    }
+
+    /// Reserve the opaques of `count` pipelined requests and the `NoOp` that follows them,
+    /// returning the first one.
+    fn reserve_opaques(&mut self, count: usize) -> u32 {
+        let first_opaque = self.opaque.wrapping_add(1);
+        self.opaque = first_opaque.wrapping_add(count as u32);
+        first_opaque
+    }
+
+    /// Send quiet `requests` and their payloads in a single write, with consecutive opaques and
+    /// followed by a `NoOp`, and return the opaque of the first one. The server only answers the
+    /// `NoOp` once it is done with the others, so responses must always be read until the `NoOp`
+    /// one to keep the stream in sync, even if something failed.
+    fn write_pipeline(&mut self, requests: Vec<(Request, Vec<u8>)>) -> Result<u32> {
+        let count = requests.len();
+        let first_opaque = self.reserve_opaques(count);
+        let mut buf = self.writer()?;
+        for (index, (mut request, final_payload)) in requests.into_iter().enumerate() {
+            request.opaque = first_opaque.wrapping_add(index as u32);
+            request.write_to(&mut buf, &final_payload)?;
         }
+        let mut request = Request::new(Command::NoOp, 0, 0, 0, 0, 0x00)?;
+        request.opaque = first_opaque.wrapping_add(count as u32);
+        request.write_to(&mut buf, &[])?;
+        buf.flush()?;
+        Ok(first_opaque)
+    }
+
+    /// Send quiet requests with `write_pipeline`. The server only answers the ones that failed, so
+    /// the returned map contains the key and status of each failed request.
+    fn write_quiet_requests<K>(
+        &mut self,
+        requests: Vec<(&K, Request, Vec<u8>)>,
+    ) -> Result<HashMap<K, Status>>
+    where
+        K: AsRef<[u8]> + Eq + Hash + Clone,
+    {
+        let mut failures = HashMap::new();
+        if requests.is_empty() {
+            return Ok(failures);
+        }
+        let opcode = requests[0].1.opcode;
+        let (keys, requests): (Vec<&K>, Vec<(Request, Vec<u8>)>) = requests
+            .into_iter()
+            .map(|(key, request, final_payload)| (key, (request, final_payload)))
+            .unzip();
+        let first_opaque = self.write_pipeline(requests)?;
+
+        let mut error = None;
+        loop {
+            let (index, response) = self.read_quiet_response(opcode, first_opaque, keys.len())?;
+            self.consume_body(response.body_length)?;
+            let index = match index {
+                Some(index) => index,
+                None => break,
+            };
+            match Status::from_u16(response.status) {
+                Some(status) => {
+                    failures.insert(keys[index].clone(), status);
+                }
+                None => {
+                    error = Some(format!(
+                        "Server sent an unknown status code 0x{:02x}",
+                        response.status
+                    ))
+                }
+            }
+        }
+        match error {
+            Some(e) => bail!(e),
+            None => Ok(failures),
+        }
+    }
+
+    fn store_multi<'a, K, V, I>(
+        &mut self,
+        command: Command,
+        values: I,
+        time: u32,
+    ) -> Result<HashMap<K, Status>>
+    where
+        I: IntoIterator<Item = &'a (K, V)>,
+        K: 'a + AsRef<[u8]> + Eq + Hash + Clone,
+        V: 'a + ToMemcached,
+    {
+        let mut requests = vec![];
+        for (key, value) in values {
+            let (value, flags) = value.get_value()?;
+            let (request, final_payload) = build_store_request(
+                command.clone(),
+                key.as_ref(),
+                &value,
+                flags.bits(),
+                time,
+                0x00,
+            )?;
+            requests.push((key, request, final_payload));
+        }
+        self.write_quiet_requests(requests)
     }
 
     pub fn set<K, V>(&mut self, key: K, value: V, time: u32) -> Result<()>
@@ -218,7 +420,18 @@ impl Protocol {
         K: AsRef<[u8]>,
         V: ToMemcached,
     {
-        self.set_add_replace(Command::Set, key, value, time)
+        self.set_add_replace(Command::Set, key, value, time, 0x00)?;
+        Ok(())
+    }
+
+    /// Store `value` only if the item was not modified since `cas` was read with `gets`.
+    /// Returns the new CAS token of the item.
+    pub fn set_cas<K, V>(&mut self, key: K, value: V, time: u32, cas: u64) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        self.set_add_replace(Command::Set, key, value, time, cas)
     }
 
     pub fn add<K, V>(&mut self, key: K, value: V, time: u32) -> Result<()>
@@ -226,7 +439,8 @@ impl Protocol {
         K: AsRef<[u8]>,
         V: ToMemcached,
     {
-        self.set_add_replace(Command::Add, key, value, time)
+        self.set_add_replace(Command::Add, key, value, time, 0x00)?;
+        Ok(())
     }
 
     pub fn replace<K, V>(&mut self, key: K, value: V, time: u32) -> Result<()>
@@ -234,7 +448,48 @@ impl Protocol {
         K: AsRef<[u8]>,
         V: ToMemcached,
     {
-        self.set_add_replace(Command::Replace, key, value, time)
+        self.set_add_replace(Command::Replace, key, value, time, 0x00)?;
+        Ok(())
+    }
+
+    /// Same as `set_cas` but the key must already exist.
+    pub fn replace_cas<K, V>(&mut self, key: K, value: V, time: u32, cas: u64) -> Result<u64>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        self.set_add_replace(Command::Replace, key, value, time, cas)
+    }
+
+    /// Store all `values` pipelining `SetQ` requests, so the whole batch costs a single round trip.
+    /// Returns the keys that could not be stored along with the status sent by the server.
+    pub fn set_multi<'a, K, V, I>(&mut self, values: I, time: u32) -> Result<HashMap<K, Status>>
+    where
+        I: IntoIterator<Item = &'a (K, V)>,
+        K: 'a + AsRef<[u8]> + Eq + Hash + Clone,
+        V: 'a + ToMemcached,
+    {
+        self.store_multi(Command::SetQ, values, time)
+    }
+
+    /// Same as `set_multi` but using `AddQ`.
+    pub fn add_multi<'a, K, V, I>(&mut self, values: I, time: u32) -> Result<HashMap<K, Status>>
+    where
+        I: IntoIterator<Item = &'a (K, V)>,
+        K: 'a + AsRef<[u8]> + Eq + Hash + Clone,
+        V: 'a + ToMemcached,
+    {
+        self.store_multi(Command::AddQ, values, time)
+    }
+
+    /// Same as `set_multi` but using `ReplaceQ`.
+    pub fn replace_multi<'a, K, V, I>(&mut self, values: I, time: u32) -> Result<HashMap<K, Status>>
+    where
+        I: IntoIterator<Item = &'a (K, V)>,
+        K: 'a + AsRef<[u8]> + Eq + Hash + Clone,
+        V: 'a + ToMemcached,
+    {
+        self.store_multi(Command::ReplaceQ, values, time)
     }
 
     pub fn get<K, V>(&mut self, key: K) -> Result<V>
@@ -242,9 +497,58 @@ impl Protocol {
         K: AsRef<[u8]>,
         V: FromMemcached,
     {
-        let key = key.as_ref();
-        let request = Protocol::build_request(Command::Get, key.len(), 0 as usize, 0, 0, 0x00)?;
-        self.write_request(request, key)?;
+        let (value, _) = self.gets(key)?;
+        Ok(value)
+    }
+
+    /// Get a value together with its CAS token, to be used later on with the `*_cas` methods.
+    pub fn gets<K, V>(&mut self, key: K) -> Result<(V, u64)>
+    where
+        K: AsRef<[u8]>,
+        V: FromMemcached,
+    {
+        let item = self.get_item(Command::Get, key.as_ref(), &[])?;
+        let value = FromMemcached::get_value(stored_type(item.flags)?, item.value)?;
+        Ok((value, item.cas))
+    }
+
+    /// Get a value and update its expiration time at the same time.
+    pub fn get_and_touch<K, V>(&mut self, key: K, time: u32) -> Result<V>
+    where
+        K: AsRef<[u8]>,
+        V: FromMemcached,
+    {
+        let mut extras = vec![];
+        extras.write_u32::<BigEndian>(time)?;
+        let item = self.get_item(Command::GetAndTouch, key.as_ref(), &extras)?;
+        FromMemcached::get_value(stored_type(item.flags)?, item.value)
+    }
+
+    /// Get an item with its flags as they are stored, whatever library stored it.
+    pub fn get_raw<K: AsRef<[u8]>>(&mut self, key: K) -> Result<RawItem> {
+        self.get_item(Command::Get, key.as_ref(), &[])
+    }
+
+    /// Store `value` with any `flags`, like the ones used by other client libraries. If `cas` is
+    /// not 0 the item is only stored if it was not modified since `cas` was read. Returns the new
+    /// CAS token of the item.
+    pub fn set_raw<K: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        value: &[u8],
+        flags: u32,
+        time: u32,
+        cas: u64,
+    ) -> Result<u64> {
+        self.store(Command::Set, key.as_ref(), value, flags, time, cas)
+    }
+
+    fn get_item(&mut self, command: Command, key: &[u8], extras: &[u8]) -> Result<RawItem> {
+        let request = Request::new(command, key.len(), 0, 0, extras.len(), 0x00)?;
+        let mut final_payload = Vec::with_capacity(extras.len() + key.len());
+        final_payload.write_all(extras)?;
+        final_payload.write_all(key)?;
+        self.write_request(request, &final_payload)?;
         let response = self.read_response()?;
         match Status::from_u16(response.status) {
             Some(Status::Success) => {}
@@ -252,31 +556,268 @@ impl Protocol {
                 self.consume_body(response.body_length)?;
                 bail!(ErrorKind::Status(status));
             }
-            None => {
-                bail!(
-                    "Server sent an unknown status code 0x{:02x}",
-                    response.status
-                );
-            }
+            None => return Err(self.unknown_status(&response)),
         };
-        let flags = StoredType::from_bits(self.connection.read_u32::<BigEndian>()?).unwrap();
+        let flags = self.read_flags(&response)?;
         let mut outbuf = vec![0; (response.body_length - response.extras_length as u32) as usize];
         self.connection.read_exact(&mut outbuf)?;
-        FromMemcached::get_value(flags, outbuf)
+        Ok(RawItem {
+            value: outbuf,
+            flags,
+            cas: response.cas,
+        })
     }
 
-    pub fn delete<K>(&mut self, key: K) -> Result<()>
+    /// Get multiple keys in a single round trip by pipelining `GetKQ` requests followed by a `NoOp`.
+    /// Keys that are not found are just not present in the returned map.
+    pub fn get_multi<'a, K, V, I>(&mut self, keys: I) -> Result<HashMap<K, V>>
+    where
+        I: IntoIterator<Item = &'a K>,
+        K: 'a + AsRef<[u8]> + Eq + Hash + Clone,
+        V: FromMemcached,
+    {
+        self.get_quiet_multi(Command::GetKQ, keys, &[])
+    }
+
+    /// Same as `get_multi` but also updates the expiration time of the keys found, using `GATQ`.
+    pub fn get_and_touch_multi<'a, K, V, I>(&mut self, keys: I, time: u32) -> Result<HashMap<K, V>>
+    where
+        I: IntoIterator<Item = &'a K>,
+        K: 'a + AsRef<[u8]> + Eq + Hash + Clone,
+        V: FromMemcached,
+    {
+        let mut extras = vec![];
+        extras.write_u32::<BigEndian>(time)?;
+        self.get_quiet_multi(Command::GetAndTouchQ, keys, &extras)
+    }
+
+    /// Pipeline quiet get requests, each one with consecutive opaques, followed by a `NoOp`.
+    fn get_quiet_multi<'a, K, V, I>(
+        &mut self,
+        command: Command,
+        keys: I,
+        extras: &[u8],
+    ) -> Result<HashMap<K, V>>
+    where
+        I: IntoIterator<Item = &'a K>,
+        K: 'a + AsRef<[u8]> + Eq + Hash + Clone,
+        V: FromMemcached,
+    {
+        let requested_keys: Vec<&K> = keys.into_iter().collect();
+        let mut values = HashMap::with_capacity(requested_keys.len());
+        if requested_keys.is_empty() {
+            return Ok(values);
+        }
+        let opcode = command.clone() as u8;
+        let mut requests = Vec::with_capacity(requested_keys.len());
+        for key in &requested_keys {
+            let key = key.as_ref();
+            let request = Request::new(command.clone(), key.len(), 0, 0, extras.len(), 0x00)?;
+            let mut final_payload = Vec::with_capacity(extras.len() + key.len());
+            final_payload.write_all(extras)?;
+            final_payload.write_all(key)?;
+            requests.push((request, final_payload));
+        }
+        let first_opaque = self.write_pipeline(requests)?;
+
+        let mut error = None;
+        loop {
+            let (index, response) =
+                self.read_quiet_response(opcode, first_opaque, requested_keys.len())?;
+            let index = match index {
+                Some(index) => index,
+                None => {
+                    self.consume_body(response.body_length)?;
+                    break;
+                }
+            };
+            match Status::from_u16(response.status) {
+                Some(Status::Success) => {}
+                Some(status) => {
+                    self.consume_body(response.body_length)?;
+                    error = error.or_else(|| Some(ErrorKind::Status(status).into()));
+                    continue;
+                }
+                None => {
+                    self.consume_body(response.body_length)?;
+                    error = error.or_else(|| {
+                        Some(
+                            format!(
+                                "Server sent an unknown status code 0x{:02x}",
+                                response.status
+                            )
+                            .into(),
+                        )
+                    });
+                    continue;
+                }
+            };
+            let flags = self.read_flags(&response)?;
+            // GetKQ sends the key back, but the opaque is enough to know which one it is.
+            let mut key = vec![0; response.key_length as usize];
+            self.connection.read_exact(&mut key)?;
+            let value_length =
+                response.body_length - response.extras_length as u32 - response.key_length as u32;
+            let mut outbuf = vec![0; value_length as usize];
+            self.connection.read_exact(&mut outbuf)?;
+            if error.is_some() {
+                continue;
+            }
+            let flags = match stored_type(flags) {
+                Ok(flags) => flags,
+                Err(e) => {
+                    error = Some(e);
+                    continue;
+                }
+            };
+            match FromMemcached::get_value(flags, outbuf) {
+                Ok(value) => {
+                    values.insert(requested_keys[index].clone(), value);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(values),
+        }
+    }
+
+    fn delete_with_cas<K>(&mut self, key: K, cas: u64) -> Result<()>
     where
         K: AsRef<[u8]>,
     {
         let key = key.as_ref();
-        let request = Protocol::build_request(Command::Delete, key.len(), 0 as usize, 0, 0, 0x00)?;
+        let request = Request::new(Command::Delete, key.len(), 0, 0, 0, cas)?;
         self.write_request(request, key)?;
         let response = self.read_response()?;
 
         match Status::from_u16(response.status) {
-            Some(Status::Success) => Ok(()),
-            Some(Status::KeyNotFound) => {
+            Some(Status::Success) | Some(Status::KeyNotFound) => {
+                self.consume_body(response.body_length)?;
+                Ok(())
+            }
+            Some(Status::KeyExists) if cas != 0x00 => {
+                self.consume_body(response.body_length)?;
+                bail!(ErrorKind::CasMismatch)
+            }
+            Some(status) => {
+                self.consume_body(response.body_length)?;
+                bail!(ErrorKind::Status(status))
+            }
+            None => Err(self.unknown_status(&response)),
+        }
+    }
+
+    pub fn delete<K>(&mut self, key: K) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.delete_with_cas(key, 0x00)
+    }
+
+    /// Delete the key only if it was not modified since `cas` was read with `gets`.
+    pub fn delete_cas<K>(&mut self, key: K, cas: u64) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.delete_with_cas(key, cas)
+    }
+
+    /// Delete all `keys` pipelining `DeleteQ` requests. As with `delete`, keys that do not exist
+    /// are not considered failures.
+    pub fn delete_multi<'a, K, I>(&mut self, keys: I) -> Result<HashMap<K, Status>>
+    where
+        I: IntoIterator<Item = &'a K>,
+        K: 'a + AsRef<[u8]> + Eq + Hash + Clone,
+    {
+        let mut requests = vec![];
+        for key in keys {
+            let raw_key = key.as_ref();
+            let request = Request::new(Command::DeleteQ, raw_key.len(), 0, 0, 0, 0x00)?;
+            requests.push((key, request, raw_key.to_vec()));
+        }
+        let mut failures = self.write_quiet_requests(requests)?;
+        failures.retain(|_, status| *status != Status::KeyNotFound);
+        Ok(failures)
+    }
+
+    fn append_prepend<K, V>(&mut self, command: Command, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        let key = key.as_ref();
+        let (value, flags) = value.get_value()?;
+        let mut final_payload = Vec::with_capacity(key.len() + value.len());
+        final_payload.write_all(key)?;
+        final_payload.write_all(&value)?;
+        // Append and prepend don't carry flags, so make sure we don't mix different types. The CAS
+        // token makes the server refuse the update if the item was replaced since it was read, in
+        // which case its type is checked again.
+        loop {
+            let stored = self.get_item(Command::Get, key, &[])?;
+            if stored.flags != flags.bits() {
+                bail!(ErrorKind::TypeMismatch(stored_type(stored.flags)?));
+            }
+            let request = Request::new(command.clone(), key.len(), value.len(), 0, 0, stored.cas)?;
+            self.write_request(request, &final_payload)?;
+            let response = self.read_response()?;
+            match Status::from_u16(response.status) {
+                Some(Status::Success) => {
+                    self.consume_body(response.body_length)?;
+                    return Ok(());
+                }
+                Some(Status::KeyExists) => {
+                    self.consume_body(response.body_length)?;
+                }
+                Some(status) => {
+                    self.consume_body(response.body_length)?;
+                    bail!(ErrorKind::Status(status))
+                }
+                None => return Err(self.unknown_status(&response)),
+            }
+        }
+    }
+
+    /// Append `value` to the end of an existing key. The key must exist and `value` must be of
+    /// the same type as the stored one, otherwise `ErrorKind::TypeMismatch` is returned.
+    ///
+    /// The type is checked by reading the whole stored value first, so this costs two round trips
+    /// and the transfer of the value. If the key is modified between the read and the append, it
+    /// is read again and the append retried, so concurrent appends of the same type all succeed.
+    pub fn append<K, V>(&mut self, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        self.append_prepend(Command::Append, key, value)
+    }
+
+    /// Same as `append` but `value` is added to the beginning of the stored one.
+    pub fn prepend<K, V>(&mut self, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: ToMemcached,
+    {
+        self.append_prepend(Command::Prepend, key, value)
+    }
+
+    /// Update the expiration time of a key without fetching it.
+    pub fn touch<K>(&mut self, key: K, time: u32) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        let extras_length = 4; // Expiration time: u32
+        let request = Request::new(Command::Touch, key.len(), 0, 0, extras_length, 0x00)?;
+        let mut final_payload = vec![];
+        final_payload.write_u32::<BigEndian>(time)?;
+        final_payload.write_all(key)?;
+        self.write_request(request, &final_payload)?;
+        let response = self.read_response()?;
+        match Status::from_u16(response.status) {
+            Some(Status::Success) => {
                 self.consume_body(response.body_length)?;
                 Ok(())
             }
@@ -284,13 +825,158 @@ impl Protocol {
                 self.consume_body(response.body_length)?;
                 bail!(ErrorKind::Status(status))
             }
-            None => bail!(
-                "Server sent an unknown status code 0x{:02x}",
-                response.status
+            None => Err(self.unknown_status(&response)),
+        }
+    }
+
+    /// Send a command without key or value and return the body of the response.
+    fn send_command(&mut self, command: Command, extras: &[u8]) -> Result<Vec<u8>> {
+        let request = Request::new(command, 0, 0, 0, extras.len(), 0x00)?;
+        self.write_request(request, extras)?;
+        let response = self.read_response()?;
+        match Status::from_u16(response.status) {
+            Some(Status::Success) => {
+                let mut body = vec![0; response.body_length as usize];
+                self.connection.read_exact(&mut body)?;
+                Ok(body)
+            }
+            Some(status) => {
+                self.consume_body(response.body_length)?;
+                bail!(ErrorKind::Status(status))
+            }
+            None => Err(self.unknown_status(&response)),
+        }
+    }
+
+    /// Invalidate all items on the server, after `delay` seconds if it is not 0.
+    pub fn flush(&mut self, delay: u32) -> Result<()> {
+        let mut extras = vec![];
+        extras.write_u32::<BigEndian>(delay)?;
+        self.send_command(Command::Flush, &extras)?;
+        Ok(())
+    }
+
+    /// Do nothing on the server, useful to check if the connection is alive.
+    pub fn noop(&mut self) -> Result<()> {
+        self.send_command(Command::NoOp, &[])?;
+        Ok(())
+    }
+
+    /// Get the version of the server.
+    pub fn version(&mut self) -> Result<String> {
+        let version = self.send_command(Command::Version, &[])?;
+        Ok(String::from_utf8(version)?)
+    }
+
+    /// Set the verbosity level of the server logs.
+    pub fn verbosity(&mut self, level: u32) -> Result<()> {
+        let mut extras = vec![];
+        extras.write_u32::<BigEndian>(level)?;
+        self.send_command(Command::Verbosity, &extras)?;
+        Ok(())
+    }
+
+    /// Get the server statistics, `group` can be used to get a specific set of them like
+    /// `settings`, `items` or `slabs`.
+    pub fn stats(&mut self, group: Option<&str>) -> Result<HashMap<String, String>> {
+        let key = group.unwrap_or("").as_bytes();
+        let request = Request::new(Command::Stat, key.len(), 0, 0, 0, 0x00)?;
+        self.write_request(request, key)?;
+        // Convert to strings only once all the responses are read, to keep the stream in sync.
+        let mut raw_stats = vec![];
+        loop {
+            let response = self.read_response()?;
+            match Status::from_u16(response.status) {
+                Some(Status::Success) => {}
+                Some(status) => {
+                    self.consume_body(response.body_length)?;
+                    bail!(ErrorKind::Status(status))
+                }
+                None => return Err(self.unknown_status(&response)),
+            }
+            // The last response has no key nor value.
+            if response.key_length == 0 {
+                self.consume_body(response.body_length)?;
+                break;
+            }
+            let mut key = vec![0; response.key_length as usize];
+            self.connection.read_exact(&mut key)?;
+            let mut value = vec![0; (response.body_length - response.key_length as u32) as usize];
+            self.connection.read_exact(&mut value)?;
+            raw_stats.push((key, value));
+        }
+        let mut stats = HashMap::with_capacity(raw_stats.len());
+        for (key, value) in raw_stats {
+            stats.insert(String::from_utf8(key)?, String::from_utf8(value)?);
+        }
+        Ok(stats)
+    }
+
+    /// List the SASL mechanisms supported by the server.
+    pub fn sasl_list_mechs(&mut self) -> Result<Vec<String>> {
+        let mechanisms = String::from_utf8(self.send_command(Command::SaslListMechs, &[])?)?;
+        Ok(mechanisms.split_whitespace().map(String::from).collect())
+    }
+
+    fn sasl_command(
+        &mut self,
+        command: Command,
+        mechanism: &str,
+        data: &[u8],
+    ) -> Result<SaslResponse> {
+        let mechanism = mechanism.as_bytes();
+        let request = Request::new(command, mechanism.len(), data.len(), 0, 0, 0x00)?;
+        let mut final_payload = Vec::with_capacity(mechanism.len() + data.len());
+        final_payload.write_all(mechanism)?;
+        final_payload.write_all(data)?;
+        self.write_request(request, &final_payload)?;
+        let response = self.read_response()?;
+        let mut body = vec![0; response.body_length as usize];
+        self.connection.read_exact(&mut body)?;
+        match Status::from_u16(response.status) {
+            Some(Status::Success) => Ok(SaslResponse::Authenticated),
+            Some(Status::AuthContinue) | Some(Status::SaslAuthContinue) => {
+                Ok(SaslResponse::Continue(body))
+            }
+            Some(Status::AuthError) | Some(Status::SaslAuthError) => bail!(
+                ErrorKind::AuthenticationFailed(String::from_utf8_lossy(&body).into_owned())
             ),
+            Some(status) => bail!(ErrorKind::Status(status)),
+            None => Err(self.unknown_status(&response)),
+        }
+    }
+
+    /// Start a SASL authentication with `mechanism`.
+    pub fn sasl_auth(&mut self, mechanism: &str, data: &[u8]) -> Result<SaslResponse> {
+        self.sasl_command(Command::SaslAuth, mechanism, data)
+    }
+
+    /// Answer a challenge sent by the server during a SASL authentication.
+    pub fn sasl_step(&mut self, mechanism: &str, data: &[u8]) -> Result<SaslResponse> {
+        self.sasl_command(Command::SaslStep, mechanism, data)
+    }
+
+    /// Authenticate using the SASL PLAIN mechanism.
+    pub fn authenticate_plain(&mut self, username: &str, password: &str) -> Result<()> {
+        let mut data = Vec::with_capacity(username.len() + password.len() + 2);
+        data.push(0);
+        data.write_all(username.as_bytes())?;
+        data.push(0);
+        data.write_all(password.as_bytes())?;
+        match self.sasl_auth("PLAIN", &data)? {
+            SaslResponse::Authenticated => Ok(()),
+            SaslResponse::Continue(_) => bail!(ErrorKind::AuthenticationFailed(
+                "Server asked for another step on PLAIN authentication".into()
+            )),
         }
     }
 
+    /// Ask the server to close the connection. The connection can't be used anymore afterwards.
+    pub fn quit(&mut self) -> Result<()> {
+        self.send_command(Command::Quit, &[])?;
+        Ok(())
+    }
+
     fn increment_decrement<K>(
         &mut self,
         key: K,
@@ -304,21 +990,27 @@ impl Protocol {
     {
         let key = key.as_ref();
         let extras_length = 20; // Amount: u64, Initial: u64, Time: u32
-        let request = Protocol::build_request(command, key.len(), 0, 0, extras_length, 0x00)?;
+        let request = Request::new(command, key.len(), 0, 0, extras_length, 0x00)?;
         let mut final_payload: Vec<u8> = vec![];
         final_payload.write_u64::<BigEndian>(amount)?;
         final_payload.write_u64::<BigEndian>(initial)?;
         final_payload.write_u32::<BigEndian>(time)?;
-        final_payload.write(key)?;
+        final_payload.write_all(key)?;
         self.write_request(request, &final_payload)?;
         let response = self.read_response()?;
         match Status::from_u16(response.status) {
-            Some(Status::Success) => Ok(self.connection.read_u64::<BigEndian>()?),
+            Some(Status::Success) if response.body_length == 8 => {
+                Ok(self.connection.read_u64::<BigEndian>()?)
+            }
+            Some(Status::Success) => Err(self.desynchronized(format!(
+                "expected a counter of 8 bytes, got {}",
+                response.body_length
+            ))),
             Some(status) => {
                 self.consume_body(response.body_length)?;
                 bail!(ErrorKind::Status(status))
             }
-            None => bail!("Server sent an unknown status code"),
+            None => Err(self.unknown_status(&response)),
         }
     }
 
@@ -337,6 +1029,35 @@ impl Protocol {
     }
 }
 
+/// Header and payload of a set, add or replace request.
+pub(crate) fn build_store_request(
+    command: Command,
+    key: &[u8],
+    value: &[u8],
+    flags: u32,
+    time: u32,
+    cas: u64,
+) -> Result<(Request, Vec<u8>)> {
+    let extras_length = 8; // Flags: u32 and Expiration time: u32
+    let request = Request::new(command, key.len(), value.len(), 0x00, extras_length, cas)?;
+    let mut final_payload = vec![];
+    // Flags
+    final_payload.write_u32::<BigEndian>(flags)?;
+    final_payload.write_u32::<BigEndian>(time)?;
+    // After flags key and value
+    final_payload.write_all(key)?;
+    final_payload.write_all(value)?;
+    Ok((request, final_payload))
+}
+
+/// Flags written by this crate, other libraries may use bits that are not defined here.
+pub(crate) fn stored_type(flags: u32) -> Result<StoredType> {
+    match StoredType::from_bits(flags) {
+        Some(stored_type) => Ok(stored_type),
+        None => bail!(ErrorKind::UnknownFlags(flags)),
+    }
+}
+
 impl ToMemcached for u8 {
     fn get_value(&self) -> Result<(Vec<u8>, StoredType)> {
         Ok((vec![*self], StoredType::MTYPE_U8))
@@ -367,20 +1088,20 @@ impl ToMemcached for u64 {
     }
 }
 
-impl<'a> ToMemcached for &'a String {
+impl ToMemcached for &String {
     fn get_value(&self) -> Result<(Vec<u8>, StoredType)> {
         let v = *self;
         Ok((v.clone().into_bytes(), StoredType::MTYPE_STRING))
     }
 }
 
-impl<'a> ToMemcached for &'a str {
+impl ToMemcached for &str {
     fn get_value(&self) -> Result<(Vec<u8>, StoredType)> {
         Ok((self.as_bytes().to_vec(), StoredType::MTYPE_STRING))
     }
 }
 
-impl<'a> ToMemcached for &'a [u8] {
+impl ToMemcached for &[u8] {
     fn get_value(&self) -> Result<(Vec<u8>, StoredType)> {
         Ok((self.to_vec(), StoredType::MTYPE_VECTOR))
     }
@@ -450,10 +1171,61 @@ impl FromMemcached for Vec<u8> {
 mod tests {
     extern crate env_logger;
 
-    use std::iter;
+    use std::io;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
 
     use super::*;
-    use errors::{Error, Result};
+    use crate::codec::Type;
+    use crate::errors::{Error, Result};
+
+    /// Start a server that accepts a single connection, answers its first request with `status`
+    /// and `body` and returns what it received.
+    fn fake_server(
+        status: Status,
+        body: &'static [u8],
+    ) -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        fake_server_with(move |header| fake_response(header, status, body))
+    }
+
+    /// Response to the request starting with `header` with `status` and `body`.
+    fn fake_response(header: &[u8], status: Status, body: &[u8]) -> Vec<u8> {
+        let mut response = vec![Type::Response as u8, header[1], 0, 0, 0, 0];
+        response.write_u16::<BigEndian>(status as u16).unwrap();
+        response.write_u32::<BigEndian>(body.len() as u32).unwrap();
+        response.write_all(&header[12..16]).unwrap();
+        response.write_all(&[0; 8]).unwrap();
+        response.write_all(body).unwrap();
+        response
+    }
+
+    /// Like `fake_server`, but answers with whatever `respond` returns for the request header.
+    fn fake_server_with<F>(respond: F) -> (SocketAddr, thread::JoinHandle<Vec<u8>>)
+    where
+        F: FnOnce(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || answer(listener.accept().unwrap().0, respond));
+        (addr, handle)
+    }
+
+    /// Read a request from `stream`, answer it with what `respond` returns for its header and
+    /// return the request.
+    fn answer<S, F>(mut stream: S, respond: F) -> Vec<u8>
+    where
+        S: Read + Write,
+        F: FnOnce(&[u8]) -> Vec<u8>,
+    {
+        let mut header = vec![0; 24];
+        stream.read_exact(&mut header).unwrap();
+        let body_length = Cursor::new(&header[8..12]).read_u32::<BigEndian>().unwrap();
+        let mut request = vec![0; body_length as usize];
+        stream.read_exact(&mut request).unwrap();
+        stream.write_all(&respond(&header)).unwrap();
+        header.extend(request);
+        header
+    }
 
     #[test]
     fn set() {
@@ -463,7 +1235,7 @@ mod tests {
         let value = "World";
         p.set(key, value, 1000).unwrap();
         p.delete(key).unwrap();
-        let data: String = iter::repeat("0").take(1024 * 1024).collect();
+        let data: String = "0".repeat(1024 * 1024);
         let err = p.set("big-data", &data, 100_000).unwrap_err();
         match err.kind() {
             &ErrorKind::Status(Status::ValueTooBig) => {}
@@ -476,7 +1248,7 @@ mod tests {
         let _ = env_logger::try_init();
         let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
         let key = "Hello";
-        let value = 1 as u8;
+        let value = 1_u8;
         p.set(key, value, 1000).unwrap();
         p.delete(key).unwrap();
     }
@@ -486,7 +1258,7 @@ mod tests {
         let _ = env_logger::try_init();
         let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
         let key = "Hello";
-        let value = 1 as u16;
+        let value = 1_u16;
         p.set(key, value, 1000).unwrap();
         p.delete(key).unwrap();
     }
@@ -496,7 +1268,7 @@ mod tests {
         let _ = env_logger::try_init();
         let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
         let key = "Hello";
-        let value = 1 as u32;
+        let value = 1_u32;
         p.set(key, value, 100).unwrap();
         p.delete(key).unwrap();
     }
@@ -506,7 +1278,7 @@ mod tests {
         let _ = env_logger::try_init();
         let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
         let key = "Hello";
-        let value = 1 as u64;
+        let value = 1_u64;
         p.set(key, value, 1000).unwrap();
         p.delete(key).unwrap();
     }
@@ -516,7 +1288,7 @@ mod tests {
         let _ = env_logger::try_init();
         let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
         let key = "Hello";
-        let value = vec![1, 2, 3];
+        let value = [1, 2, 3];
         p.set(key, &value[..], 1000).unwrap();
         p.delete(key).unwrap();
     }
@@ -547,14 +1319,14 @@ mod tests {
         let rv: String = p.get(key).unwrap();
         assert_eq!(rv, value);
 
-        let not_found: Result<String> = p.get("not found".to_string());
+        let not_found: Result<String> = p.get("not found");
         match not_found {
             Ok(_) => panic!("This key should not exist"),
             Err(Error(ErrorKind::Status(Status::KeyNotFound), _)) => {}
             Err(_) => panic!("This should return KeyNotFound"),
         };
         p.delete(key).unwrap();
-        let big_key: String = iter::repeat("0").take(260).collect();
+        let big_key: String = "0".repeat(260);
         match p.get::<_, Vec<u8>>(big_key) {
             Ok(_) => panic!("Should be an error"),
             Err(Error(ErrorKind::KeyLengthTooLong(260), _)) => {}
@@ -596,4 +1368,412 @@ mod tests {
         assert_eq!(p.decrement(key, 1, 0, 1000).unwrap(), 1);
         p.delete(key).unwrap();
     }
+
+    #[test]
+    fn get_multi() {
+        let _ = env_logger::try_init();
+        let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
+        let keys = ["Hello Multi 1", "Hello Multi 2", "Hello Multi 3"];
+        p.set(keys[0], "World 1", 1000).unwrap();
+        p.set(keys[2], "World 3", 1000).unwrap();
+        p.delete(keys[1]).unwrap();
+        let rv: HashMap<&str, String> = p.get_multi(&keys).unwrap();
+        assert_eq!(rv.len(), 2);
+        assert_eq!(rv[keys[0]], "World 1");
+        assert_eq!(rv[keys[2]], "World 3");
+        assert!(!rv.contains_key(keys[1]));
+
+        let rv: HashMap<&str, String> = p.get_multi(&[]).unwrap();
+        assert!(rv.is_empty());
+        // Connection must still be usable after the pipeline
+        let rv: String = p.get(keys[0]).unwrap();
+        assert_eq!(rv, "World 1");
+        p.delete(keys[0]).unwrap();
+        p.delete(keys[2]).unwrap();
+    }
+
+    #[test]
+    fn set_delete_multi() {
+        let _ = env_logger::try_init();
+        let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
+        let values = [("Hello SetQ 1", "World 1"), ("Hello SetQ 2", "World 2")];
+        assert!(p.set_multi(&values, 1000).unwrap().is_empty());
+        let rv: String = p.get("Hello SetQ 2").unwrap();
+        assert_eq!(rv, "World 2");
+
+        let failures = p
+            .add_multi(&[("Hello SetQ 1", "New"), ("Hello SetQ 3", "New")], 1000)
+            .unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures["Hello SetQ 1"], Status::KeyExists);
+
+        let failures = p
+            .replace_multi(&[("Hello SetQ 4", "New"), ("Hello SetQ 2", "New")], 1000)
+            .unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures["Hello SetQ 4"], Status::KeyNotFound);
+        let rv: String = p.get("Hello SetQ 2").unwrap();
+        assert_eq!(rv, "New");
+
+        let keys = [
+            "Hello SetQ 1",
+            "Hello SetQ 2",
+            "Hello SetQ 3",
+            "Hello SetQ 4",
+        ];
+        assert!(p.delete_multi(&keys).unwrap().is_empty());
+        let rv: HashMap<&str, String> = p.get_multi(&keys).unwrap();
+        assert!(rv.is_empty());
+    }
+
+    #[test]
+    fn append_prepend() {
+        let _ = env_logger::try_init();
+        let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
+        let key = "Hello Append";
+        p.set(key, "World", 1000).unwrap();
+        p.append(key, "!").unwrap();
+        p.prepend(key, "Hello ").unwrap();
+        let rv: String = p.get(key).unwrap();
+        assert_eq!(rv, "Hello World!");
+        match p.append(key, &b"bytes"[..]) {
+            Err(Error(ErrorKind::TypeMismatch(StoredType::MTYPE_STRING), _)) => {}
+            e => panic!("Appending bytes to a string should fail and not {:?}", e),
+        }
+        p.set(key, 1_u64, 1000).unwrap();
+        match p.prepend(key, 2_u64) {
+            Ok(()) => {}
+            e => panic!("Prepending the same type should work and not {:?}", e),
+        }
+        match p.append(key, &b"bytes"[..]) {
+            Err(Error(ErrorKind::TypeMismatch(StoredType::MTYPE_U64), _)) => {}
+            e => panic!("Appending bytes to a counter should fail and not {:?}", e),
+        }
+        p.delete(key).unwrap();
+        match p.append(key, "!") {
+            Err(Error(ErrorKind::Status(Status::KeyNotFound), _)) => {}
+            e => panic!("Appending to a missing key should fail and not {:?}", e),
+        }
+    }
+
+    #[test]
+    fn append_retry() {
+        let _ = env_logger::try_init();
+        // A string item as returned by `Get`, with the flags as extras and an empty value.
+        fn stored_string(header: &[u8], cas: u64) -> Vec<u8> {
+            let flags = StoredType::MTYPE_STRING.bits().to_be_bytes();
+            let mut response = fake_response(header, Status::Success, &flags);
+            response[4] = flags.len() as u8;
+            response[16..24].copy_from_slice(&cas.to_be_bytes());
+            response
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            vec![
+                answer(&mut stream, |header| stored_string(header, 1)),
+                answer(&mut stream, |header| {
+                    fake_response(header, Status::KeyExists, b"")
+                }),
+                answer(&mut stream, |header| stored_string(header, 2)),
+                answer(&mut stream, |header| {
+                    fake_response(header, Status::Success, b"")
+                }),
+            ]
+        });
+        let mut p = Protocol::connect(addr).unwrap();
+        // Another client appended between the read and the append, so it's read again.
+        p.append("Hello Retry", "!").unwrap();
+        let requests = server.join().unwrap();
+        assert_eq!(requests[1][16..24], 1_u64.to_be_bytes());
+        assert_eq!(requests[3][16..24], 2_u64.to_be_bytes());
+    }
+
+    #[test]
+    fn touch() {
+        let _ = env_logger::try_init();
+        let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
+        let key = "Hello Touch";
+        p.set(key, "World", 1).unwrap();
+        p.touch(key, 1000).unwrap();
+        let rv: String = p.get_and_touch(key, 1000).unwrap();
+        assert_eq!(rv, "World");
+        let rv: HashMap<&str, String> = p
+            .get_and_touch_multi(&[key, "Hello Touch missing"], 1000)
+            .unwrap();
+        assert_eq!(rv.len(), 1);
+        assert_eq!(rv[key], "World");
+        p.delete(key).unwrap();
+        match p.touch(key, 1000) {
+            Err(Error(ErrorKind::Status(Status::KeyNotFound), _)) => {}
+            e => panic!("Touching a missing key should fail and not {:?}", e),
+        }
+        match p.get_and_touch::<_, String>(key, 1000) {
+            Err(Error(ErrorKind::Status(Status::KeyNotFound), _)) => {}
+            e => panic!("Touching a missing key should fail and not {:?}", e),
+        }
+    }
+
+    #[test]
+    fn admin_commands() {
+        let _ = env_logger::try_init();
+        let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
+        p.noop().unwrap();
+        assert!(!p.version().unwrap().is_empty());
+        p.verbosity(0).unwrap();
+        p.quit().unwrap();
+        assert!(p.noop().is_err());
+    }
+
+    #[test]
+    fn flush() {
+        let _ = env_logger::try_init();
+        let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
+        let key = "Hello Flush";
+        p.set(key, "World", 1000).unwrap();
+        p.flush(0).unwrap();
+        match p.get::<_, String>(key) {
+            Err(Error(ErrorKind::Status(Status::KeyNotFound), _)) => {}
+            e => panic!("Key should be flushed and not {:?}", e),
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let _ = env_logger::try_init();
+        let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
+        let stats = p.stats(None).unwrap();
+        assert!(stats.contains_key("curr_items"));
+        let settings = p.stats(Some("settings")).unwrap();
+        assert!(settings.contains_key("maxbytes"));
+        // Make sure the stream is still in sync.
+        p.noop().unwrap();
+    }
+
+    #[test]
+    fn authenticate_plain() {
+        let _ = env_logger::try_init();
+        let (addr, server) = fake_server(Status::Success, b"Authenticated");
+        let mut p = Protocol::connect(addr).unwrap();
+        p.authenticate_plain("user", "password").unwrap();
+        let request = server.join().unwrap();
+        assert_eq!(request[1], Command::SaslAuth as u8);
+        assert_eq!(&request[24..], &b"PLAIN\0user\0password"[..]);
+
+        let (addr, _) = fake_server(Status::SaslAuthError, b"Auth failure");
+        let mut p = Protocol::connect(addr).unwrap();
+        match p.authenticate_plain("user", "wrong") {
+            Err(Error(ErrorKind::AuthenticationFailed(ref message), _)) => {
+                assert_eq!(message, "Auth failure")
+            }
+            e => panic!("Authentication should fail and not {:?}", e),
+        }
+    }
+
+    #[test]
+    fn sasl_list_mechs() {
+        let _ = env_logger::try_init();
+        let (addr, _) = fake_server(Status::Success, b"CRAM-MD5 PLAIN");
+        let mut p = Protocol::connect(addr).unwrap();
+        assert_eq!(p.sasl_list_mechs().unwrap(), vec!["CRAM-MD5", "PLAIN"]);
+    }
+
+    #[test]
+    fn read_timeout() {
+        let _ = env_logger::try_init();
+        // Connections to this listener are never accepted, so nothing will be answered.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let timeouts = Timeouts {
+            connect: Some(Duration::from_secs(1)),
+            read: Some(Duration::from_millis(100)),
+            write: Some(Duration::from_millis(100)),
+        };
+        let mut p =
+            Protocol::connect_with_timeouts(listener.local_addr().unwrap(), timeouts).unwrap();
+        match p.get::<_, String>("Hello Timeout") {
+            Err(Error(ErrorKind::Timeout, _)) => {}
+            e => panic!("Get should time out and not {:?}", e),
+        }
+    }
+
+    #[test]
+    fn cas() {
+        let _ = env_logger::try_init();
+        let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
+        let key = "Hello CAS";
+        p.set(key, "World", 1000).unwrap();
+        let (rv, cas): (String, u64) = p.gets(key).unwrap();
+        assert_eq!(rv, "World");
+        let new_cas = p.set_cas(key, "New World", 1000, cas).unwrap();
+        assert!(new_cas != cas);
+        match p.set_cas(key, "Stale World", 1000, cas) {
+            Err(Error(ErrorKind::CasMismatch, _)) => {}
+            e => panic!("Stale CAS should fail and not {:?}", e),
+        }
+        match p.replace_cas(key, "Stale World", 1000, cas) {
+            Err(Error(ErrorKind::CasMismatch, _)) => {}
+            e => panic!("Stale CAS should fail and not {:?}", e),
+        }
+        match p.delete_cas(key, cas) {
+            Err(Error(ErrorKind::CasMismatch, _)) => {}
+            e => panic!("Stale CAS should fail and not {:?}", e),
+        }
+        let (rv, _): (String, u64) = p.gets(key).unwrap();
+        assert_eq!(rv, "New World");
+        p.delete_cas(key, new_cas).unwrap();
+    }
+
+    #[test]
+    fn raw_flags() {
+        let _ = env_logger::try_init();
+        let mut p = Protocol::connect("127.0.0.1:11211").unwrap();
+        let key = "Hello Raw";
+        // Flags of a pickled object in python-memcached.
+        let cas = p.set_raw(key, b"World", 0x1, 1000, 0).unwrap();
+        let item = p.get_raw(key).unwrap();
+        assert_eq!(item.value, b"World");
+        assert_eq!(item.flags, 0x1);
+        assert_eq!(item.cas, cas);
+
+        let new_cas = p.set_raw(key, b"World", 0xffff_0000, 1000, cas).unwrap();
+        match p.set_raw(key, b"Stale World", 0, 1000, cas) {
+            Err(Error(ErrorKind::CasMismatch, _)) => {}
+            e => panic!("Stale CAS should fail and not {:?}", e),
+        }
+        match p.get::<_, String>(key) {
+            Err(Error(ErrorKind::UnknownFlags(0xffff_0000), _)) => {}
+            e => panic!("Flags should be unknown and not {:?}", e),
+        }
+        p.delete_cas(key, new_cas).unwrap();
+    }
+
+    #[test]
+    fn binary_error_body() {
+        let _ = env_logger::try_init();
+        let (addr, _) = fake_server(Status::KeyNotFound, &[0xff, 0xfe, 0x00]);
+        let mut p = Protocol::connect(addr).unwrap();
+        match p.get::<_, String>("key") {
+            Err(Error(ErrorKind::Status(Status::KeyNotFound), _)) => {}
+            e => panic!("Get should fail with KeyNotFound and not {:?}", e),
+        }
+    }
+
+    fn assert_desynchronized<T: ::std::fmt::Debug>(p: &mut Protocol, result: Result<T>) {
+        match result {
+            Err(Error(ErrorKind::Desynchronized(_), _)) => {}
+            e => panic!("Request should be out of sync and not {:?}", e),
+        }
+        // The connection is given up, so no other response can be misattributed.
+        match p.noop() {
+            Err(Error(ErrorKind::Desynchronized(_), _)) => {}
+            e => panic!("Connection should be given up and not {:?}", e),
+        }
+    }
+
+    #[test]
+    fn unknown_magic() {
+        let _ = env_logger::try_init();
+        let (addr, _) = fake_server_with(|_| vec![0x42; 24]);
+        let mut p = Protocol::connect(addr).unwrap();
+        let result = p.get::<_, String>("key");
+        assert_desynchronized(&mut p, result);
+    }
+
+    #[test]
+    fn unexpected_opaque() {
+        let _ = env_logger::try_init();
+        let (addr, _) = fake_server_with(|header| {
+            let mut response = vec![Type::Response as u8, header[1]];
+            response.write_all(&[0; 10]).unwrap();
+            response.write_u32::<BigEndian>(42).unwrap();
+            response.write_all(&[0; 8]).unwrap();
+            response
+        });
+        let mut p = Protocol::connect(addr).unwrap();
+        let result = p.delete("key");
+        assert_desynchronized(&mut p, result);
+    }
+
+    #[test]
+    fn unexpected_opcode() {
+        let _ = env_logger::try_init();
+        let (addr, _) = fake_server_with(|header| {
+            let mut response = vec![Type::Response as u8, Command::Version as u8];
+            response.write_all(&[0; 10]).unwrap();
+            response.write_all(&header[12..16]).unwrap();
+            response.write_all(&[0; 8]).unwrap();
+            response
+        });
+        let mut p = Protocol::connect(addr).unwrap();
+        let result = p.touch("key", 100);
+        assert_desynchronized(&mut p, result);
+    }
+
+    /// Replays `input` as the responses of the server and keeps the requests written to it.
+    struct MemoryStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn in_memory_stream() {
+        let _ = env_logger::try_init();
+        let mut input = vec![Type::Response as u8, Command::Get as u8, 0, 0, 4, 0, 0, 0];
+        input.write_u32::<BigEndian>(9).unwrap();
+        input.write_u32::<BigEndian>(1).unwrap(); // Opaque of the first request
+        input.write_u64::<BigEndian>(0).unwrap();
+        input
+            .write_u32::<BigEndian>(StoredType::MTYPE_STRING.bits())
+            .unwrap();
+        input.write_all(b"World").unwrap();
+        let mut p = Protocol::new(MemoryStream {
+            input: Cursor::new(input),
+            output: vec![],
+        });
+        let value: String = p.get("Hello").unwrap();
+        assert_eq!(value, "World");
+        let output = &p.connection.get_ref().output;
+        assert_eq!(output.len(), 24 + 5);
+        assert_eq!(output[1], Command::Get as u8);
+        assert_eq!(&output[24..], b"Hello");
+        // Nothing else to read, as if the server closed the connection.
+        assert!(p.noop().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let _ = env_logger::try_init();
+        let path = ::std::env::temp_dir().join(format!("bmemcached-{}.sock", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            answer(listener.accept().unwrap().0, |header| {
+                fake_response(header, Status::Success, b"")
+            })
+        });
+        let mut p = Protocol::connect_unix(&path).unwrap();
+        p.noop().unwrap();
+        assert_eq!(server.join().unwrap()[1], Command::NoOp as u8);
+        ::std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::errors::Result;
+
+/// The most common general statistics of a server, as returned by `MemcachedClient::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerStats {
+    /// Seconds since the server started.
+    pub uptime: u64,
+    /// Number of items currently stored.
+    pub curr_items: u64,
+    /// Number of bytes currently used to store items.
+    pub bytes: u64,
+    pub get_hits: u64,
+    pub get_misses: u64,
+    /// Number of valid items removed to free memory for new ones.
+    pub evictions: u64,
+    /// Number of bytes the server is allowed to use for storage.
+    pub limit_maxbytes: u64,
+}
+
+fn parse<T: FromStr>(stats: &HashMap<String, String>, name: &str) -> Result<T> {
+    match stats.get(name) {
+        Some(value) => match value.parse() {
+            Ok(value) => Ok(value),
+            Err(_) => bail!("Invalid value {:?} for stat {}", value, name),
+        },
+        None => bail!("Stat {} not found", name),
+    }
+}
+
+impl ServerStats {
+    /// Parse the raw statistics sent by the server without any group.
+    pub fn from_stats(stats: &HashMap<String, String>) -> Result<ServerStats> {
+        Ok(ServerStats {
+            uptime: parse(stats, "uptime")?,
+            curr_items: parse(stats, "curr_items")?,
+            bytes: parse(stats, "bytes")?,
+            get_hits: parse(stats, "get_hits")?,
+            get_misses: parse(stats, "get_misses")?,
+            evictions: parse(stats, "evictions")?,
+            limit_maxbytes: parse(stats, "limit_maxbytes")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_stats() {
+        let mut stats: HashMap<String, String> = [
+            ("pid", "1"),
+            ("uptime", "10"),
+            ("curr_items", "2"),
+            ("bytes", "100"),
+            ("get_hits", "3"),
+            ("get_misses", "4"),
+            ("evictions", "5"),
+            ("limit_maxbytes", "67108864"),
+        ]
+        .iter()
+        .map(|&(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(
+            ServerStats::from_stats(&stats).unwrap(),
+            ServerStats {
+                uptime: 10,
+                curr_items: 2,
+                bytes: 100,
+                get_hits: 3,
+                get_misses: 4,
+                evictions: 5,
+                limit_maxbytes: 67_108_864,
+            }
+        );
+
+        stats.insert("bytes".to_string(), "a lot".to_string());
+        assert!(ServerStats::from_stats(&stats).is_err());
+        stats.remove("bytes");
+        assert!(ServerStats::from_stats(&stats).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! TLS encrypted connections with rustls, enabled by the `tls` feature. memcached supports TLS
+//! since 1.6, when built with `--enable-tls` and started with `-Z`.
+//!
+//! ```no_run
+//! use bmemcached::MemcachedClient;
+//!
+//! let client = MemcachedClient::builder(vec!["cache1.internal:11211"])
+//!     .tls_ca_bundle("/etc/memcached/ca.pem")
+//!     .tls_client_cert("/etc/memcached/client.pem", "/etc/memcached/client.key")
+//!     .build()
+//!     .unwrap();
+//! ```
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use crate::errors::Result;
+use crate::protocol::Timeouts;
+use crate::transport::{self, Address};
+
+/// A TLS connection to a server, see `Protocol::connect_tls`.
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Build a rustls configuration trusting the server certificates signed by the authorities in the
+/// PEM file `ca_bundle`. `client_cert` are the PEM files of the certificate chain and private key
+/// presented to servers that require client certificates.
+pub fn client_config(
+    ca_bundle: &Path,
+    client_cert: Option<(&Path, &Path)>,
+) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for certificate in certificates(ca_bundle)? {
+        if let Err(e) = roots.add(certificate) {
+            bail!("Invalid certificate in {}: {}", ca_bundle.display(), e);
+        }
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = match client_cert {
+        Some((cert, key)) => {
+            match builder.with_client_auth_cert(certificates(cert)?, private_key(key)?) {
+                Ok(config) => config,
+                Err(e) => bail!("Invalid client certificate {}: {}", cert.display(), e),
+            }
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certificates.is_empty() {
+        bail!("No certificate found in {}", path.display());
+    }
+    Ok(certificates)
+}
+
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => Ok(key),
+        None => bail!("No private key found in {}", path.display()),
+    }
+}
+
+/// Connect to `addr`, apply `timeouts` and do the TLS handshake, checking the server certificate
+/// against `server_name`.
+pub(crate) fn connect<A: ToSocketAddrs>(
+    addr: A,
+    server_name: &str,
+    config: Arc<ClientConfig>,
+    timeouts: Timeouts,
+) -> Result<TlsStream> {
+    let name = match ServerName::try_from(server_name.to_string()) {
+        Ok(name) => name,
+        Err(e) => bail!("Invalid TLS server name {}: {}", server_name, e),
+    };
+    let connection = match ClientConnection::new(config, name) {
+        Ok(connection) => connection,
+        Err(e) => bail!("Could not start TLS connection to {}: {}", server_name, e),
+    };
+    let mut stream = StreamOwned::new(connection, transport::connect_tcp(addr, timeouts)?);
+    // Certificate errors show up when connecting and not with the first request.
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+    Ok(stream)
+}
+
+/// TLS settings of the connections opened by `MemcachedClient`.
+#[derive(Debug)]
+pub(crate) struct TlsConnector {
+    pub(crate) config: Arc<ClientConfig>,
+    /// Name checked against every server certificate instead of the host of its address.
+    pub(crate) server_name: Option<String>,
+}
+
+impl TlsConnector {
+    /// Connect to the server called `name`, like `cache1:11211`, at `address`.
+    pub(crate) fn connect(
+        &self,
+        name: &str,
+        address: &Address,
+        timeouts: Timeouts,
+    ) -> Result<TlsStream> {
+        let addrs = match address {
+            Address::Tcp(addrs) => addrs,
+            #[cfg(unix)]
+            Address::Unix(_) => bail!("TLS is only supported over TCP, not for {}", name),
+        };
+        let server_name = match self.server_name {
+            Some(ref server_name) => server_name,
+            None => host(name),
+        };
+        connect(&addrs[..], server_name, self.config.clone(), timeouts)
+    }
+}
+
+/// Host of a server address written as `host:port`, without the brackets of IPv6 addresses.
+fn host(name: &str) -> &str {
+    let host = match name.rfind(':') {
+        Some(index) if !name.ends_with(']') => &name[..index],
+        _ => name,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts() {
+        assert_eq!(host("cache1.internal:11211"), "cache1.internal");
+        assert_eq!(host("127.0.0.1:11211"), "127.0.0.1");
+        assert_eq!(host("[::1]:11211"), "::1");
+        assert_eq!(host("[::1]"), "::1");
+        assert_eq!(host("localhost"), "localhost");
+    }
+}
@@ -0,0 +1,157 @@
+//! Streams used by `MemcachedClient`, over TCP or a Unix domain socket depending on the address of
+//! the server, and encrypted with TLS when it is configured.
+
+use std::fmt::Display;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+use crate::errors::Result;
+use crate::protocol::Timeouts;
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+
+/// Prefix of the addresses of servers listening on a Unix domain socket, like
+/// `unix:/var/run/memcached.sock`.
+pub(crate) const UNIX_PREFIX: &str = "unix:";
+
+/// Where a server listens.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Address {
+    Tcp(Vec<SocketAddr>),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// Resolve `addr`, a path after `unix:` for a Unix domain socket and anything
+    /// `ToSocketAddrs` understands otherwise.
+    pub(crate) fn resolve<A: ToSocketAddrs + Display>(addr: &A) -> Result<Address> {
+        let name = addr.to_string();
+        if let Some(path) = name.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            return Ok(Address::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            bail!(
+                "Unix domain sockets are not supported on this platform: {}",
+                path
+            );
+        }
+        Ok(Address::Tcp(addr.to_socket_addrs()?.collect()))
+    }
+
+    /// Whether both addresses lead to the same server.
+    pub(crate) fn overlaps(&self, other: &Address) -> bool {
+        match (self, other) {
+            (Address::Tcp(addrs), Address::Tcp(others)) => {
+                addrs.iter().any(|addr| others.contains(addr))
+            }
+            #[cfg(unix)]
+            (Address::Unix(path), Address::Unix(other)) => path == other,
+            #[cfg(unix)]
+            _ => false,
+        }
+    }
+}
+
+/// An open connection to a server.
+#[derive(Debug)]
+pub(crate) enum Transport {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl Transport {
+    pub(crate) fn connect(address: &Address, timeouts: Timeouts) -> Result<Transport> {
+        match address {
+            Address::Tcp(addrs) => Ok(Transport::Tcp(connect_tcp(&addrs[..], timeouts)?)),
+            #[cfg(unix)]
+            Address::Unix(path) => Ok(Transport::Unix(connect_unix(path, timeouts)?)),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Transport::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Transport::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Transport::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Connect to the first address of `addr` that accepts the connection and apply `timeouts` to it.
+pub(crate) fn connect_tcp<A: ToSocketAddrs>(addr: A, timeouts: Timeouts) -> Result<TcpStream> {
+    let stream = match timeouts.connect {
+        Some(timeout) => {
+            let mut last_error = None;
+            let mut stream = None;
+            for addr in addr.to_socket_addrs()? {
+                match TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(s) => {
+                        stream = Some(s);
+                        break;
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            match (stream, last_error) {
+                (Some(stream), _) => stream,
+                (None, Some(e)) => return Err(e.into()),
+                (None, None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Could not resolve any address",
+                    )
+                    .into())
+                }
+            }
+        }
+        None => TcpStream::connect(addr)?,
+    };
+    stream.set_read_timeout(timeouts.read)?;
+    stream.set_write_timeout(timeouts.write)?;
+    Ok(stream)
+}
+
+/// Connect to the socket at `path` and apply `timeouts` to it. Connecting to a local socket
+/// doesn't block, so the connect timeout is not used.
+#[cfg(unix)]
+pub(crate) fn connect_unix<P: AsRef<Path>>(path: P, timeouts: Timeouts) -> Result<UnixStream> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(timeouts.read)?;
+    stream.set_write_timeout(timeouts.write)?;
+    Ok(stream)
+}
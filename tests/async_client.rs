@@ -0,0 +1,315 @@
+#![cfg(feature = "tokio")]
+extern crate bmemcached;
+extern crate env_logger;
+extern crate tokio;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use bmemcached::distribution::Ketama;
+use bmemcached::errors::{Error, ErrorKind};
+use bmemcached::{AsyncMemcachedClient, AsyncProtocol, Status};
+
+#[tokio::test]
+async fn get_set_delete() {
+    let _ = env_logger::try_init();
+    let client = AsyncMemcachedClient::new(vec!["127.0.0.1:11211"], 1)
+        .await
+        .unwrap();
+    let key = "Hello Get, Set, Delete Async";
+    let value = "World";
+    client.set(key, value, 1000).await.unwrap();
+    let rv: String = client.get(key).await.unwrap();
+    assert_eq!(rv, value);
+    client.delete(key).await.unwrap();
+    match client.get::<_, String>(key).await {
+        Err(Error(ErrorKind::Status(Status::KeyNotFound), _)) => (),
+        e => panic!("Wrong status returned {:?}", e),
+    }
+    // Deleting a missing key is not a failure.
+    client.delete(key).await.unwrap();
+}
+
+#[tokio::test]
+async fn add_replace() {
+    let _ = env_logger::try_init();
+    let client = AsyncMemcachedClient::new(vec!["127.0.0.1:11211"], 1)
+        .await
+        .unwrap();
+    let key = "Hello Add, Replace Async";
+    client.add(key, 1_u32, 1000).await.unwrap();
+    match client.add(key, 1_u32, 1000).await {
+        Err(Error(ErrorKind::Status(Status::KeyExists), _)) => (),
+        e => panic!("Wrong status returned {:?}", e),
+    }
+    client.replace(key, 2_u32, 1000).await.unwrap();
+    let rv: u32 = client.get(key).await.unwrap();
+    assert_eq!(rv, 2);
+    client.delete(key).await.unwrap();
+    match client.replace(key, 3_u32, 1000).await {
+        Err(Error(ErrorKind::Status(Status::KeyNotFound), _)) => (),
+        e => panic!("Wrong status returned {:?}", e),
+    }
+}
+
+#[tokio::test]
+async fn increment_decrement() {
+    let _ = env_logger::try_init();
+    let client = AsyncMemcachedClient::new(vec!["127.0.0.1:11211"], 1)
+        .await
+        .unwrap();
+    let key = "Hello Increment Async";
+    assert_eq!(client.increment(key, 1, 10, 1000).await.unwrap(), 10);
+    assert_eq!(client.increment(key, 5, 10, 1000).await.unwrap(), 15);
+    assert_eq!(client.decrement(key, 3, 10, 1000).await.unwrap(), 12);
+    client.delete(key).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn multiple_tasks() {
+    let _ = env_logger::try_init();
+    let client = Arc::new(
+        AsyncMemcachedClient::new(vec!["127.0.0.1:11211"], 2)
+            .await
+            .unwrap(),
+    );
+    let mut tasks = vec![];
+    for i in 0..16 {
+        let client = client.clone();
+        tasks.push(tokio::spawn(async move {
+            let data = format!("async_data_n{}", i);
+            client.set(&data, &data, 100).await.unwrap();
+            let val: String = client.get(&data).await.unwrap();
+            client.delete(&data).await.unwrap();
+            val
+        }));
+    }
+    for (i, task) in tasks.into_iter().enumerate() {
+        assert_eq!(task.await.unwrap(), format!("async_data_n{}", i));
+    }
+}
+
+/// Answer the next request sent on `stream` with an empty success. Returns false once the client
+/// closed the connection.
+async fn answer<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> bool {
+    let mut header = [0; 24];
+    if stream.read_exact(&mut header).await.is_err() {
+        return false;
+    }
+    let body_length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    let mut body = vec![0; body_length as usize];
+    stream.read_exact(&mut body).await.unwrap();
+    let mut response = [0; 24];
+    response[0] = 0x81;
+    response[1] = header[1];
+    // Same opaque as the request.
+    response[12..16].copy_from_slice(&header[12..16]);
+    stream.write_all(&response).await.unwrap();
+    true
+}
+
+#[tokio::test]
+async fn reconnect() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        // Drop the first connection as soon as it gets a request, then answer the second one.
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut header = [0; 24];
+        stream.read_exact(&mut header).await.unwrap();
+        drop(stream);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert!(answer(&mut stream).await);
+        stream
+    });
+    let client = AsyncMemcachedClient::new(vec![addr], 1).await.unwrap();
+    assert!(client.delete("key").await.is_err());
+    client.delete("key").await.unwrap();
+
+    // The server is gone now, so reconnecting fails and the next attempt only happens later.
+    drop(server.await.unwrap());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    match client.delete("key").await {
+        Err(Error(ErrorKind::IoError(_), _)) => (),
+        e => panic!("Reconnecting should fail and not {:?}", e),
+    }
+    match client.delete("key").await {
+        Err(Error(ErrorKind::Disconnected(_), _)) => (),
+        e => panic!("Should wait before reconnecting and not {:?}", e),
+    }
+}
+
+#[tokio::test]
+async fn unknown_magic() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut header = [0; 24];
+        stream.read_exact(&mut header).await.unwrap();
+        stream.write_all(&[0x42; 24]).await.unwrap();
+        // Keep the connection open so the client is the one closing it.
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).await.unwrap();
+    });
+    let protocol = AsyncProtocol::connect(addr).await.unwrap();
+    match protocol.delete("").await {
+        Err(Error(ErrorKind::Desynchronized(_), _)) => (),
+        e => panic!("Request should be out of sync and not {:?}", e),
+    }
+    assert!(protocol.delete("").await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn multiplexed_requests() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        // A single connection gets every request, which are answered in reverse order.
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut requests = vec![];
+        for _ in 0..8 {
+            let mut header = [0; 24];
+            stream.read_exact(&mut header).await.unwrap();
+            let mut key = vec![0; header[3] as usize];
+            stream.read_exact(&mut key).await.unwrap();
+            requests.push((header, key));
+        }
+        for (header, key) in requests.into_iter().rev() {
+            let mut response = vec![0x81, header[1], 0, 0, 4, 0, 0, 0, 0, 0, 0];
+            response.push(4 + key.len() as u8);
+            response.extend_from_slice(&header[12..16]);
+            response.extend_from_slice(&[0; 8]);
+            response.extend_from_slice(&[0, 0, 0, 1]); // Flags of a string
+            response.extend_from_slice(&key);
+            stream.write_all(&response).await.unwrap();
+        }
+    });
+    let client = Arc::new(AsyncMemcachedClient::new(vec![addr], 1).await.unwrap());
+    let mut tasks = vec![];
+    for i in 0..8 {
+        let client = client.clone();
+        tasks.push(tokio::spawn(async move {
+            client.get::<_, String>(format!("key{}", i)).await.unwrap()
+        }));
+    }
+    for (i, task) in tasks.into_iter().enumerate() {
+        assert_eq!(task.await.unwrap(), format!("key{}", i));
+    }
+}
+
+#[tokio::test]
+async fn cancelled_request() {
+    let _ = env_logger::try_init();
+    let protocol = AsyncProtocol::connect("127.0.0.1:11211").await.unwrap();
+    let key = "Hello Cancelled Async";
+    protocol.set(key, "World", 1000).await.unwrap();
+    // The get is sent and then dropped before its response arrives.
+    tokio::select! {
+        biased;
+        _ = protocol.get::<_, String>(key) => panic!("The get should not finish first"),
+        _ = async {} => {}
+    }
+    // Its response doesn't get mixed with the next ones.
+    protocol.set(key, "Again", 1000).await.unwrap();
+    let rv: String = protocol.get(key).await.unwrap();
+    assert_eq!(rv, "Again");
+    protocol.delete(key).await.unwrap();
+}
+
+#[tokio::test]
+async fn request_timeout() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        // The first connection never answers, the second one does.
+        let (mut hung, _) = listener.accept().await.unwrap();
+        let mut header = [0; 24];
+        hung.read_exact(&mut header).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert!(answer(&mut stream).await);
+        (hung, stream)
+    });
+    let client = AsyncMemcachedClient::builder(vec![addr])
+        .timeout(Duration::from_millis(100))
+        .build()
+        .await
+        .unwrap();
+    match client.delete("key").await {
+        Err(Error(ErrorKind::Timeout, _)) => (),
+        e => panic!("Request should time out and not {:?}", e),
+    }
+    client.delete("key").await.unwrap();
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn credentials() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![0; 24 + b"PLAIN\0user\0password".len()];
+        stream.read_exact(&mut request).await.unwrap();
+        let mut response = [0; 24];
+        response[0] = 0x81;
+        response[1] = request[1];
+        response[12..16].copy_from_slice(&request[12..16]);
+        stream.write_all(&response).await.unwrap();
+        assert!(answer(&mut stream).await);
+        request
+    });
+    let client = AsyncMemcachedClient::builder(vec![addr])
+        .credentials("user", "password")
+        .build()
+        .await
+        .unwrap();
+    client.delete("key").await.unwrap();
+    let request = server.await.unwrap();
+    assert_eq!(request[1], 0x21);
+    assert_eq!(&request[24..], &b"PLAIN\0user\0password"[..]);
+}
+
+#[tokio::test]
+async fn builder_distributor() {
+    let _ = env_logger::try_init();
+    let client = AsyncMemcachedClient::builder(Vec::<&str>::new())
+        .server("127.0.0.1:11211", 3)
+        .connections_per_addr(2)
+        .distributor(Ketama::new())
+        .build()
+        .await
+        .unwrap();
+    let key = "Hello Builder Async";
+    client.set(key, "World", 1000).await.unwrap();
+    let rv: String = client.get(key).await.unwrap();
+    assert_eq!(rv, "World");
+    client.delete(key).await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket() {
+    use tokio::net::UnixListener;
+
+    let _ = env_logger::try_init();
+    let path = std::env::temp_dir().join(format!("bmemcached-async-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        while answer(&mut stream).await {}
+    });
+    let addr = format!("unix:{}", path.display());
+    let client = AsyncMemcachedClient::new(vec![addr], 1).await.unwrap();
+    client.delete("Hello Unix Async").await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
@@ -3,11 +3,20 @@ extern crate env_logger;
 extern crate log;
 extern crate bmemcached;
 
+mod common;
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpListener;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+use bmemcached::distribution::Ketama;
+use bmemcached::errors::{Error, ErrorKind, Result};
+use bmemcached::{FromMemcached, MemcachedClient, MultiResult, Status, StoredType};
 
-use bmemcached::errors::{Error, ErrorKind};
-use bmemcached::{MemcachedClient, Status};
+use common::answer;
 
 #[test]
 fn multiple_threads() {
@@ -50,7 +59,7 @@ fn get_set_u8() {
     let _ = env_logger::try_init();
     let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 1).unwrap();
     let key = "Hello u8";
-    let value = 1 as u8;
+    let value = 1_u8;
     client.set(key, value, 1000).unwrap();
 
     let rv: u8 = client.get(key).unwrap();
@@ -63,7 +72,7 @@ fn get_set_u16() {
     let _ = env_logger::try_init();
     let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 1).unwrap();
     let key = "Hello u16";
-    let value = 1 as u16;
+    let value = 1_u16;
     client.set(key, value, 1000).unwrap();
 
     let rv: u16 = client.get(key).unwrap();
@@ -76,7 +85,7 @@ fn get_set_u32() {
     let _ = env_logger::try_init();
     let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 1).unwrap();
     let key = "Hello u32";
-    let value = 1 as u32;
+    let value = 1_u32;
     client.set(key, value, 1000).unwrap();
 
     let rv: u32 = client.get(key).unwrap();
@@ -89,7 +98,7 @@ fn get_set_u64() {
     let _ = env_logger::try_init();
     let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 1).unwrap();
     let key = "Hello u64";
-    let value = 1 as u64;
+    let value = 1_u64;
     client.set(key, value, 1000).unwrap();
 
     let rv: u64 = client.get(key).unwrap();
@@ -149,3 +158,569 @@ fn decrement() {
     assert_eq!(client.decrement(key, 1, 1, 1000).unwrap(), 9);
     client.delete(key).unwrap();
 }
+
+#[test]
+fn cas() {
+    let _ = env_logger::try_init();
+    let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 1).unwrap();
+    let key = "Hello CAS Client";
+    client.set(key, 1_u32, 1000).unwrap();
+    let (rv, cas): (u32, u64) = client.gets(key).unwrap();
+    assert_eq!(rv, 1);
+    client.set_cas(key, rv + 1, 1000, cas).unwrap();
+    match client.set_cas(key, rv + 1, 1000, cas) {
+        Err(Error(ErrorKind::CasMismatch, _)) => (),
+        e => panic!("Wrong status returned {:?}", e),
+    }
+    let (rv, cas): (u32, u64) = client.gets(key).unwrap();
+    assert_eq!(rv, 2);
+    client.delete_cas(key, cas).unwrap();
+}
+
+#[test]
+fn get_multi() {
+    let _ = env_logger::try_init();
+    let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 5).unwrap();
+    let keys: Vec<String> = (0..50)
+        .map(|i| format!("Hello Multi Client {}", i))
+        .collect();
+    for (i, key) in keys.iter().enumerate() {
+        if i % 2 == 0 {
+            client.set(key, i as u32, 1000).unwrap();
+        } else {
+            client.delete(key).unwrap();
+        }
+    }
+    let rv: HashMap<String, u32> = client.get_multi(&keys).unwrap().into_result().unwrap();
+    assert_eq!(rv.len(), 25);
+    for (i, key) in keys.iter().enumerate() {
+        if i % 2 == 0 {
+            assert_eq!(rv[key], i as u32);
+            client.delete(key).unwrap();
+        } else {
+            assert!(!rv.contains_key(key));
+        }
+    }
+}
+
+#[test]
+fn set_delete_multi() {
+    let _ = env_logger::try_init();
+    let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 5).unwrap();
+    let values: Vec<(String, u32)> = (0..50)
+        .map(|i| (format!("Hello SetQ Client {}", i), i))
+        .collect();
+    assert!(client
+        .set_multi(&values, 1000)
+        .unwrap()
+        .into_result()
+        .unwrap()
+        .is_empty());
+    let keys: Vec<String> = values.iter().map(|(key, _)| key.clone()).collect();
+    let rv: HashMap<String, u32> = client.get_multi(&keys).unwrap().into_result().unwrap();
+    assert_eq!(rv.len(), 50);
+    for (key, value) in &values {
+        assert_eq!(rv[key], *value);
+    }
+
+    let failures = client
+        .add_multi(&values[..2], 1000)
+        .unwrap()
+        .into_result()
+        .unwrap();
+    assert_eq!(failures.len(), 2);
+    assert_eq!(failures[&keys[0]], Status::KeyExists);
+
+    assert!(client
+        .delete_multi(&keys)
+        .unwrap()
+        .into_result()
+        .unwrap()
+        .is_empty());
+    let rv: HashMap<String, u32> = client.get_multi(&keys).unwrap().into_result().unwrap();
+    assert!(rv.is_empty());
+}
+
+#[test]
+fn append_prepend() {
+    let _ = env_logger::try_init();
+    let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 1).unwrap();
+    let key = "Hello Append Client";
+    client.set(key, &b"b"[..], 1000).unwrap();
+    client.append(key, &b"c"[..]).unwrap();
+    client.prepend(key, &b"a"[..]).unwrap();
+    let rv: Vec<u8> = client.get(key).unwrap();
+    assert_eq!(rv, b"abc");
+    match client.append(key, 1_u8) {
+        Err(Error(ErrorKind::TypeMismatch(_), _)) => (),
+        e => panic!("Wrong status returned {:?}", e),
+    }
+    client.delete(key).unwrap();
+}
+
+#[test]
+fn touch() {
+    let _ = env_logger::try_init();
+    let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 1).unwrap();
+    let key = "Hello Touch Client";
+    client.set(key, "World", 1000).unwrap();
+    client.touch(key, 1000).unwrap();
+    let rv: String = client.get_and_touch(key, 1000).unwrap();
+    assert_eq!(rv, "World");
+    client.delete(key).unwrap();
+    match client.touch(key, 1000) {
+        Err(Error(ErrorKind::Status(Status::KeyNotFound), _)) => (),
+        e => panic!("Wrong status returned {:?}", e),
+    }
+}
+
+#[test]
+fn admin_commands() {
+    let _ = env_logger::try_init();
+    let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 2).unwrap();
+    let versions = client.version().unwrap();
+    assert_eq!(versions.len(), 1);
+    assert!(versions.contains_key("127.0.0.1:11211"));
+    client.noop().unwrap();
+    client.verbosity(0).unwrap();
+
+    let key = "Hello Flush Client";
+    client.set(key, "World", 1000).unwrap();
+    client.flush_all(0).unwrap();
+    match client.get::<_, String>(key) {
+        Err(Error(ErrorKind::Status(Status::KeyNotFound), _)) => (),
+        e => panic!("Wrong status returned {:?}", e),
+    }
+    client.quit().unwrap();
+}
+
+#[test]
+fn quit_broken_connection() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = MemcachedClient::builder(vec![listener.local_addr().unwrap()])
+        .read_timeout(Duration::from_millis(100))
+        .build()
+        .unwrap();
+    drop(listener.accept().unwrap());
+    assert!(client.noop().is_err());
+    // The broken connection is not reopened only to be closed.
+    client.quit().unwrap();
+    listener.set_nonblocking(true).unwrap();
+    assert!(listener.accept().is_err());
+}
+
+#[test]
+fn admin_commands_failing_server() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let dead_addr = listener.local_addr().unwrap().to_string();
+    let client = MemcachedClient::new(vec![dead_addr.as_str(), "127.0.0.1:11211"], 1).unwrap();
+    drop(listener);
+
+    let key = "Hello Flush Failing Client";
+    let other = MemcachedClient::new(vec!["127.0.0.1:11211"], 1).unwrap();
+    other.set(key, "World", 1000).unwrap();
+    // The first server fails, but the next one is still flushed.
+    assert!(client.flush_all(0).is_err());
+    match other.get::<_, String>(key) {
+        Err(Error(ErrorKind::Status(Status::KeyNotFound), _)) => (),
+        e => panic!("Wrong status returned {:?}", e),
+    }
+}
+
+#[test]
+fn stats() {
+    let _ = env_logger::try_init();
+    let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 2).unwrap();
+    let key = "Hello Stats Client";
+    client.set(key, "World", 1000).unwrap();
+    let _: String = client.get(key).unwrap();
+    let stats = client.server_stats().unwrap();
+    assert_eq!(stats.len(), 1);
+    let server_stats = &stats["127.0.0.1:11211"];
+    assert!(server_stats.curr_items >= 1);
+    assert!(server_stats.get_hits >= 1);
+
+    let stats = client.stats(Some("settings")).unwrap();
+    assert!(stats["127.0.0.1:11211"].contains_key("maxbytes"));
+    client.delete(key).unwrap();
+}
+
+#[test]
+fn read_timeout() {
+    let _ = env_logger::try_init();
+    // Connections to this listener are never accepted, so nothing will be answered.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = MemcachedClient::builder(vec![listener.local_addr().unwrap()])
+        .connect_timeout(Duration::from_secs(1))
+        .read_timeout(Duration::from_millis(100))
+        .build()
+        .unwrap();
+    match client.get::<_, String>("Hello Timeout Client") {
+        Err(Error(ErrorKind::Timeout, _)) => (),
+        e => panic!("Wrong status returned {:?}", e),
+    }
+}
+
+#[test]
+fn reconnect() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        // Drop the first connection as soon as it gets a request, then answer the second one.
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut header = [0; 24];
+        stream.read_exact(&mut header).unwrap();
+        drop(stream);
+        let (mut stream, _) = listener.accept().unwrap();
+        assert!(answer(&mut stream));
+        stream
+    });
+    let client = MemcachedClient::builder(vec![addr])
+        .reconnect_backoff(Duration::from_secs(60), Duration::from_secs(60))
+        .build()
+        .unwrap();
+    assert!(client.noop().is_err());
+    client.noop().unwrap();
+
+    // The server is gone now, so reconnecting fails and the next attempt only happens later.
+    drop(server.join().unwrap());
+    assert!(client.noop().is_err());
+    match client.noop() {
+        Err(Error(ErrorKind::IoError(_), _)) => (),
+        e => panic!("Reconnecting should fail and not {:?}", e),
+    }
+    match client.noop() {
+        Err(Error(ErrorKind::Disconnected(_), _)) => (),
+        e => panic!("Should wait before reconnecting and not {:?}", e),
+    }
+}
+
+#[test]
+fn auto_eject() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let dead_addr = listener.local_addr().unwrap().to_string();
+    let client = MemcachedClient::builder(vec!["127.0.0.1:11211", dead_addr.as_str()])
+        .auto_eject(1, Duration::from_secs(3600))
+        .build()
+        .unwrap();
+    // Closing the listener resets the connections it never accepted.
+    drop(listener);
+
+    let keys: Vec<String> = (0..50)
+        .map(|i| format!("Hello Eject Client {}", i))
+        .collect();
+    let failures = keys
+        .iter()
+        .filter(|key| client.set(key.as_str(), "World", 1000).is_err())
+        .count();
+    assert_eq!(failures, 1);
+    for key in &keys {
+        client.set(key.as_str(), "World", 1000).unwrap();
+        let value: String = client.get(key.as_str()).unwrap();
+        assert_eq!(value, "World");
+        client.delete(key.as_str()).unwrap();
+    }
+    // The ejected server can't be flushed.
+    assert!(client.flush_all(0).is_err());
+}
+
+#[test]
+fn multi_failing_server() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let dead_addr = listener.local_addr().unwrap().to_string();
+    let client = MemcachedClient::new(vec!["127.0.0.1:11211", dead_addr.as_str()], 1).unwrap();
+    drop(listener);
+
+    let values: Vec<(String, u32)> = (0..50)
+        .map(|i| (format!("Hello Multi Failing {}", i), i))
+        .collect();
+    let rv = client.set_multi(&values, 1000).unwrap();
+    assert!(rv.values.is_empty());
+    assert_eq!(rv.failed.len(), 1);
+    assert_eq!(rv.failed[0].0, dead_addr);
+
+    // The keys of the server that answers are still returned.
+    let keys: Vec<String> = values.iter().map(|(key, _)| key.clone()).collect();
+    let rv: MultiResult<HashMap<String, u32>> = client.get_multi(&keys).unwrap();
+    assert!(!rv.values.is_empty() && rv.values.len() < keys.len());
+    for (key, value) in &values {
+        if let Some(stored) = rv.values.get(key) {
+            assert_eq!(stored, value);
+        }
+    }
+    assert_eq!(rv.failed.len(), 1);
+    assert_eq!(rv.failed[0].0, dead_addr);
+    assert!(rv.into_result().is_err());
+
+    let rv = client.delete_multi(&keys).unwrap();
+    assert!(rv.values.is_empty());
+    assert_eq!(rv.failed[0].0, dead_addr);
+}
+
+#[test]
+fn auto_eject_last_server() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = MemcachedClient::builder(vec![listener.local_addr().unwrap()])
+        .auto_eject(2, Duration::from_secs(3600))
+        .build()
+        .unwrap();
+    drop(listener);
+
+    assert!(client.noop().is_err());
+    assert!(client.set("Hello Eject Client", "World", 1000).is_err());
+    match client.get::<_, String>("Hello Eject Client") {
+        Err(Error(ErrorKind::NoServers, _)) => (),
+        e => panic!("Wrong status returned {:?}", e),
+    }
+}
+
+#[test]
+fn auto_eject_restore() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        // Break the first connection, then count the requests on the next one.
+        drop(listener.accept().unwrap());
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut requests = 0;
+        while answer(&mut stream) {
+            requests += 1;
+        }
+        requests
+    });
+    let client = MemcachedClient::builder(vec!["127.0.0.1:11211", addr.as_str()])
+        .auto_eject(1, Duration::from_millis(200))
+        .build()
+        .unwrap();
+
+    let key = (0..50)
+        .map(|i| format!("Hello Restore Client {}", i))
+        .find(|key| client.delete(key.as_str()).is_err())
+        .unwrap();
+    // Its keys go to the other server until the retry interval is over, and it is left out of
+    // the commands sent to every server, except flush so it has no stale items once it is back.
+    client.noop().unwrap();
+    client.flush_all(0).unwrap();
+    client.delete(key.as_str()).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    client.delete(key.as_str()).unwrap();
+    drop(client);
+    // The flush, the probe and the last delete.
+    assert_eq!(server.join().unwrap(), 3);
+}
+
+#[test]
+fn add_remove_server() {
+    let _ = env_logger::try_init();
+    let client = MemcachedClient::builder(Vec::<&str>::new())
+        .read_timeout(Duration::from_millis(50))
+        .build()
+        .unwrap();
+    client.add_server("127.0.0.1:11211", 100).unwrap();
+    let keys: Vec<String> = (0..20)
+        .map(|i| format!("Hello Pool Client {}", i))
+        .collect();
+    for key in &keys {
+        client.set(key.as_str(), "World", 1000).unwrap();
+    }
+
+    // Nothing is ever answered by this server, so only the keys moved to it fail.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    client.add_server(addr, 100).unwrap();
+    assert!(client.add_server(addr, 100).is_err());
+    let moved = keys
+        .iter()
+        .filter(|key| client.get::<_, String>(key.as_str()).is_err())
+        .count();
+    assert!(moved > 0 && moved < keys.len());
+
+    assert!(client.remove_server(addr).unwrap());
+    assert!(!client.remove_server(addr).unwrap());
+    for key in &keys {
+        let value: String = client.get(key.as_str()).unwrap();
+        assert_eq!(value, "World");
+        client.delete(key.as_str()).unwrap();
+    }
+}
+
+#[test]
+fn server_weights() {
+    let _ = env_logger::try_init();
+    // Nothing is ever answered by this server, but it has no share of the keys.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let client = MemcachedClient::builder(vec!["127.0.0.1:11211"])
+        .server(addr.as_str(), 0)
+        .connections_per_addr(3)
+        .read_timeout(Duration::from_millis(50))
+        .build()
+        .unwrap();
+    for i in 0..20 {
+        let key = format!("Hello Weight Client {}", i);
+        client.set(key.as_str(), "World", 1000).unwrap();
+        client.delete(key.as_str()).unwrap();
+    }
+}
+
+#[test]
+fn ketama() {
+    let _ = env_logger::try_init();
+    let client = MemcachedClient::builder(vec!["127.0.0.1:11211"])
+        .distributor(Ketama::new())
+        .build()
+        .unwrap();
+    let key = "Hello Ketama Client";
+    client.set(key, "World", 1000).unwrap();
+    let value: String = client.get(key).unwrap();
+    assert_eq!(value, "World");
+    client.delete(key).unwrap();
+}
+
+#[test]
+fn connection_pool() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    // Answer every request after a while, requests only run in parallel on different connections.
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                while answer(&mut stream) {
+                    thread::sleep(Duration::from_millis(300));
+                }
+            });
+        }
+    });
+    let client = Arc::new(MemcachedClient::new(vec![addr], 2).unwrap());
+
+    let start = Instant::now();
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            let client = client.clone();
+            thread::spawn(move || client.delete("Hello Pool Client").unwrap())
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    // The same key doesn't wait for a busy connection while the other one is idle.
+    assert!(start.elapsed() < Duration::from_millis(550));
+}
+
+#[test]
+fn server_names() {
+    let _ = env_logger::try_init();
+    let client = MemcachedClient::new(vec!["localhost:11211"], 1).unwrap();
+    let versions = client.version().unwrap();
+    assert!(versions.contains_key("localhost:11211"));
+    // Names and resolved addresses both identify the server.
+    assert!(client.add_server("127.0.0.1:11211", 1).is_err());
+    assert!(client.remove_server("localhost:11211").unwrap());
+    assert!(client.version().unwrap().is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket() {
+    use std::os::unix::net::UnixListener;
+
+    let _ = env_logger::try_init();
+    let path = std::env::temp_dir().join(format!("bmemcached-client-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        while answer(&mut stream) {}
+    });
+    let addr = format!("unix:{}", path.display());
+    let client = MemcachedClient::new(vec![addr.as_str()], 1).unwrap();
+    client.delete("Hello Unix Client").unwrap();
+    assert!(client.add_server(addr.as_str(), 1).is_err());
+    assert!(client.remove_server(addr.as_str()).unwrap());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn unknown_flags() {
+    let _ = env_logger::try_init();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        // Flags set by another library first, then the ones of a string.
+        for &flags in &[0xffff_ffff_u32, 1] {
+            let mut header = [0; 24];
+            stream.read_exact(&mut header).unwrap();
+            let key_length = u16::from(header[2]) << 8 | u16::from(header[3]);
+            let mut key = vec![0; key_length as usize];
+            stream.read_exact(&mut key).unwrap();
+            let mut response = vec![0x81, header[1], 0, 0, 4, 0, 0, 0, 0, 0, 0, 9];
+            // Same opaque as the request, no CAS.
+            response.extend_from_slice(&header[12..16]);
+            response.extend_from_slice(&[0; 8]);
+            response.extend_from_slice(&flags.to_be_bytes());
+            response.extend_from_slice(b"World");
+            stream.write_all(&response).unwrap();
+        }
+    });
+    let client = MemcachedClient::new(vec![addr], 1).unwrap();
+    match client.get::<_, String>("Hello Flags Client") {
+        Err(Error(ErrorKind::UnknownFlags(0xffff_ffff), _)) => (),
+        e => panic!("Wrong status returned {:?}", e),
+    }
+    // The connection is still usable.
+    let value: String = client.get("Hello Flags Client").unwrap();
+    assert_eq!(value, "World");
+    server.join().unwrap();
+}
+
+struct Panics;
+
+impl FromMemcached for Panics {
+    fn get_value(_: StoredType, _: Vec<u8>) -> Result<Self> {
+        panic!("Panicking while reading a value");
+    }
+}
+
+#[test]
+fn panic_while_reading() {
+    let _ = env_logger::try_init();
+    let client = Arc::new(MemcachedClient::new(vec!["127.0.0.1:11211"], 1).unwrap());
+    let key = "Hello Panic Client";
+    client.set(key, "World", 1000).unwrap();
+    let panicking_client = client.clone();
+    assert!(
+        thread::spawn(move || panicking_client.get::<_, Panics>(key))
+            .join()
+            .is_err()
+    );
+    let value: String = client.get(key).unwrap();
+    assert_eq!(value, "World");
+    client.delete(key).unwrap();
+}
+
+#[test]
+fn raw_flags() {
+    let _ = env_logger::try_init();
+    let client = MemcachedClient::new(vec!["127.0.0.1:11211"], 1).unwrap();
+    let key = "Hello Raw Client";
+    client.set(key, "World", 1000).unwrap();
+    let item = client.get_raw(key).unwrap();
+    assert_eq!(item.value, b"World");
+    assert_eq!(item.flags, StoredType::MTYPE_STRING.bits());
+
+    // Integers as stored by PHP Memcached.
+    client.set_raw(key, b"10", 0x1, 1000, 0).unwrap();
+    assert_eq!(client.get_raw(key).unwrap().flags, 0x1);
+    client.delete(key).unwrap();
+}
@@ -0,0 +1,22 @@
+//! Helpers shared by the integration tests.
+
+use std::io::{Read, Write};
+
+/// Answer the next request sent on `stream` with an empty success. Returns false once the client
+/// closed the connection.
+pub fn answer<S: Read + Write>(stream: &mut S) -> bool {
+    let mut header = [0; 24];
+    if stream.read_exact(&mut header).is_err() {
+        return false;
+    }
+    let body_length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    let mut body = vec![0; body_length as usize];
+    stream.read_exact(&mut body).unwrap();
+    let mut response = [0; 24];
+    response[0] = 0x81;
+    response[1] = header[1];
+    // Same opaque as the request.
+    response[12..16].copy_from_slice(&header[12..16]);
+    stream.write_all(&response).unwrap();
+    true
+}
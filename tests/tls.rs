@@ -0,0 +1,194 @@
+#![cfg(feature = "tls")]
+extern crate bmemcached;
+extern crate env_logger;
+extern crate rcgen;
+extern crate rustls;
+
+mod common;
+
+use std::fs;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+use bmemcached::protocol::Protocol;
+use bmemcached::{tls, MemcachedClient};
+
+use common::answer;
+
+/// Certificates signed by a throwaway authority, written as PEM files in their own directory.
+struct Certificates {
+    dir: PathBuf,
+    ca: Certificate,
+    ca_key: KeyPair,
+}
+
+impl Certificates {
+    fn new(name: &str) -> Certificates {
+        let dir = std::env::temp_dir().join(format!("bmemcached-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        Certificates { dir, ca, ca_key }
+    }
+
+    /// Sign a certificate for `names` and write it to `<file>.pem` and `<file>.key`.
+    fn sign(
+        &self,
+        file: &str,
+        names: &[&str],
+    ) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let key = KeyPair::generate().unwrap();
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let cert = CertificateParams::new(names)
+            .unwrap()
+            .signed_by(&key, &self.ca, &self.ca_key)
+            .unwrap();
+        fs::write(self.path(&format!("{}.pem", file)), cert.pem()).unwrap();
+        fs::write(self.path(&format!("{}.key", file)), key.serialize_pem()).unwrap();
+        let der = PrivatePkcs8KeyDer::from(key.serialize_der());
+        (cert.der().clone(), der.into())
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    fn roots(&self) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.ca.der().clone()).unwrap();
+        roots
+    }
+}
+
+impl Drop for Certificates {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Start a server terminating TLS with `config` that accepts `connections`, one after the other,
+/// and answers every request with an empty success until the client disconnects. Returns the names
+/// the clients sent with SNI.
+fn tls_server(
+    config: ServerConfig,
+    connections: usize,
+) -> (String, thread::JoinHandle<Vec<Option<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let config = Arc::new(config);
+    let handle = thread::spawn(move || {
+        let mut names = vec![];
+        for stream in listener.incoming().take(connections) {
+            let connection = ServerConnection::new(config.clone()).unwrap();
+            let mut stream = StreamOwned::new(connection, stream.unwrap());
+            while answer(&mut stream) {}
+            names.push(stream.conn.server_name().map(|name| name.to_string()));
+        }
+        names
+    });
+    (addr, handle)
+}
+
+#[test]
+fn protocol_over_tls() {
+    let _ = env_logger::try_init();
+    let certificates = Certificates::new("protocol-tls");
+    let (cert, key) = certificates.sign("server", &["localhost"]);
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    let (addr, server) = tls_server(config, 1);
+    let config = tls::client_config(&certificates.path("ca.pem"), None).unwrap();
+    let mut protocol = Protocol::connect_tls(addr.as_str(), "localhost", config).unwrap();
+    protocol.noop().unwrap();
+    drop(protocol);
+    assert_eq!(server.join().unwrap(), vec![Some("localhost".to_string())]);
+}
+
+#[test]
+fn client_server_name() {
+    let _ = env_logger::try_init();
+    let certificates = Certificates::new("client-tls");
+    let (cert, key) = certificates.sign("server", &["cache.internal"]);
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    let (addr, server) = tls_server(config, 2);
+    // The certificate is not valid for the IP the server is written as.
+    assert!(MemcachedClient::builder(vec![addr.as_str()])
+        .tls_ca_bundle(certificates.path("ca.pem"))
+        .build()
+        .is_err());
+    let client = MemcachedClient::builder(vec![addr.as_str()])
+        .tls_ca_bundle(certificates.path("ca.pem"))
+        .tls_server_name("cache.internal")
+        .build()
+        .unwrap();
+    client.delete("Hello TLS Client").unwrap();
+    drop(client);
+    // No name is sent for IPs.
+    assert_eq!(
+        server.join().unwrap(),
+        vec![None, Some("cache.internal".to_string())]
+    );
+}
+
+#[test]
+fn client_certificate() {
+    let _ = env_logger::try_init();
+    let certificates = Certificates::new("client-cert-tls");
+    let (cert, key) = certificates.sign("server", &["localhost"]);
+    certificates.sign("client", &["client"]);
+    let verifier = WebPkiClientVerifier::builder(Arc::new(certificates.roots()))
+        .build()
+        .unwrap();
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    let (addr, server) = tls_server(config, 2);
+    let addr = addr.replace("127.0.0.1", "localhost");
+    // The server may only notice the missing certificate after the client is done with the
+    // handshake, so the first request fails instead.
+    assert!(MemcachedClient::builder(vec![addr.as_str()])
+        .tls_ca_bundle(certificates.path("ca.pem"))
+        .build()
+        .and_then(|client| client.delete("Hello TLS Client Certificate"))
+        .is_err());
+    let client = MemcachedClient::builder(vec![addr.as_str()])
+        .tls_ca_bundle(certificates.path("ca.pem"))
+        .tls_client_cert(
+            certificates.path("client.pem"),
+            certificates.path("client.key"),
+        )
+        .build()
+        .unwrap();
+    client.delete("Hello TLS Client Certificate").unwrap();
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn missing_ca_bundle() {
+    let _ = env_logger::try_init();
+    assert!(MemcachedClient::builder(vec!["127.0.0.1:11211"])
+        .tls_server_name("localhost")
+        .build()
+        .is_err());
+    assert!(MemcachedClient::builder(vec!["127.0.0.1:11211"])
+        .tls_ca_bundle("/nonexistent/ca.pem")
+        .build()
+        .is_err());
+}